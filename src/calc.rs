@@ -0,0 +1,3079 @@
+// The calculation of the balance changes of a transaction, and the variants built on it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::error::CalculationError;
+use crate::types::{negated, overflow, Balance, Coin, CoinSet, DenomDefinition, MultiSend};
+
+// Implement `calculate_balance_changes` with the following requirements.
+// - Output of the function is the balance changes that must be applied to different accounts
+//   (negative means deduction, positive means addition), or an error. the error indicates that the transaction must be rejected.
+// - If sum of inputs and outputs in multi_send_tx does not match the tx must be rejected(i.e return error).
+// - Apply burn_rate and commission_rate as described by their definition.
+// - If the sender does not have enough balances (in the original_balances) to cover the input amount on top of burn_rate and
+// commission_rate, the transaction must be rejected.
+// - burn_rate and commission_rate does not apply to the issuer. So to calculate the correct values you must do this for every denom:
+//      - sum all the inputs coming from accounts that are not an issuer (let's call it non_issuer_input_sum)
+//      - sum all the outputs going to accounts that are not an issuer (let's call it non_issuer_output_sum)
+//      - total burn amount is total_burn = min(non_issuer_input_sum, non_issuer_output_sum)
+//      - total_burn is distributed between all input accounts as: account_share = roundup(total_burn * input_from_account / non_issuer_input_sum)
+//      - total_burn_amount = sum (account_shares) // notice that in previous step we rounded up, so we need to recalculate the total again.
+//      - commission_rate is exactly the same, but we send the calculate value to issuer, and not burn.
+//      - Example:
+//          burn_rate: 10%
+//
+//          inputs:
+//          60, 90
+//          25 <-- issuer
+//
+//          outputs:
+//          50
+//          100 <-- issuer
+//          25
+//          In this case burn amount is: min(non_issuer_inputs, non_issuer_outputs) = min(75+75, 50+25) = 75
+//          Expected burn: 75 * 10% = 7.5
+//          And now we divide it proportionally between all input sender: first_sender_share  = 7.5 * 60 / 150  = 3
+//                                                                        second_sender_share = 7.5 * 90 / 150  = 4.5
+// - In README.md we have provided more examples to help you better understand the requirements.
+// - Write different unit tests to cover all the edge cases, we would like to see how you structure your tests.
+//   There are examples in README.md, you can convert them into tests, but you should add more cases.
+pub fn calculate_balance_changes(
+    original_balances: Vec<Balance>,
+    definitions: Vec<DenomDefinition>,
+    multi_send_tx: MultiSend,
+) -> Result<Vec<Balance>, CalculationError> {
+    calculate_balance_changes_ref(&original_balances, &definitions, &multi_send_tx)
+}
+
+// Same as `calculate_balance_changes`, but borrows its inputs, e.g. to run many transactions
+// against one snapshot of the balances. Only the balances of the accounts a transaction touches
+// are copied, never the snapshot itself.
+pub fn calculate_balance_changes_ref(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+) -> Result<Vec<Balance>, CalculationError> {
+    let balance_changes = calculate_with_options_ref(
+        original_balances,
+        definitions,
+        multi_send_tx,
+        &CalculationOptions::default(),
+    )?;
+    Ok(balance_changes.changes)
+}
+
+// A network fee, paid by `payer` on top of any transfer of the transaction. Fees are exempt from
+// burn and commission and are credited to the fee collector.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Fee {
+    pub payer: String,
+    pub coins: Vec<Coin>,
+}
+
+impl Fee {
+    pub fn new(payer: &str, coins: Vec<Coin>) -> Self {
+        Fee {
+            payer: payer.to_string(),
+            coins,
+        }
+    }
+}
+
+// Settings of a calculation beyond the transaction itself. When deserialized, missing settings
+// take their default.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[non_exhaustive]
+pub struct CalculationOptions {
+    // The network fee of the transaction, if it pays one.
+    pub fee: Option<Fee>,
+    // The address fees are credited to.
+    pub fee_collector: String,
+    // Rejects transactions with outputs to addresses absent from the original balances.
+    pub forbid_account_creation: bool,
+    // Rejects transactions mixing denoms that charge a burn or commission with denoms that
+    // charge neither.
+    pub require_uniform_fee_policy: bool,
+    // Fills `BalanceChanges::tagged_changes` with the components of every change.
+    pub tag_changes: bool,
+    // Fills `BalanceChanges::rounding_trace` with every rounded burn and commission.
+    pub trace_rounding: bool,
+    // Sentinel addresses used internally (e.g. a burn or minting pseudo-account) that no input
+    // or output of a transaction may use.
+    pub reserved_addresses: BTreeSet<String>,
+    // Accounts exempt from burn and commission in every denom for this calculation only, as if
+    // they were the issuer of each.
+    pub tx_exempt_accounts: BTreeSet<String>,
+    // Receives, as an extra output, whatever the inputs of a denom exceed its outputs by, so that
+    // a transaction built with conservative outputs still balances exactly.
+    pub remainder_recipient: Option<String>,
+    // Fills `BalanceChanges::mutations` with every change applied to the working balances, for
+    // debugging.
+    pub audit_mutations: bool,
+    // Rejects transactions in which rounding makes any sender pay a burn plus commission above
+    // this fraction of what it sends, e.g. 0.5 rejects a fee of 1 on a 1-token send.
+    pub max_effective_fee_rate: Option<f64>,
+    // Burns this fraction of every commission, rounded up per sender, instead of crediting it to
+    // the issuer. Senders pay the same commission either way: the secondary burn only moves part
+    // of it from the issuer's credit to the burned supply, so `TransferReport` counts it as
+    // burned rather than as commission. It is not recorded in the rounding trace. Rates outside
+    // [0, 1] are rejected.
+    pub secondary_burn_rate: Option<f64>,
+    // The order of `BalanceChanges::changes`.
+    pub output_order: OutputOrder,
+    // Whether the stated amounts include the burn and commission.
+    pub fee_inclusion: FeeInclusion,
+    // Rejects transactions touching more distinct denoms, before any per-denom structure is
+    // built.
+    pub max_distinct_denoms: Option<usize>,
+    // Rejects transactions with outputs to more distinct addresses.
+    pub max_recipients: Option<usize>,
+    // Accepts commission rates in [-1, 0), under which the issuer pays every sender a rebate
+    // instead of charging it a commission. The rebate is the commission's share of the fee base
+    // rounded towards zero, so that the issuer is never drained of more than the rate implies,
+    // and the issuer must cover it from its own balance. Burn rates must be in [0, 1] either way.
+    pub allow_rebates: bool,
+    // Fills `BalanceChanges::conservation_proof`.
+    pub prove_conservation: bool,
+}
+
+// Who bears the burn and commission of a transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum FeeInclusion {
+    // Recipients receive the stated outputs and senders pay the fees on top of their inputs.
+    #[default]
+    Exclusive,
+    // Senders pay exactly their stated inputs, their fees included, and the fees of a denom are
+    // taken off its outputs in proportion to the stated amounts. The proportional shares are
+    // rounded down and what that leaves is taken one token at a time from the outputs in order.
+    // The fees are computed on the stated amounts either way.
+    Inclusive,
+}
+
+// How the accounts of `BalanceChanges::changes` are ordered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum OutputOrder {
+    #[default]
+    Address,
+    // In the order accounts first appear in the inputs, then the outputs, then as the issuer
+    // of an input's denom. Accounts appearing nowhere in the transaction, such as the fee
+    // collector, come last in address order.
+    FirstAppearance,
+}
+
+impl Default for CalculationOptions {
+    fn default() -> Self {
+        CalculationOptions {
+            fee: None,
+            fee_collector: "fee_collector".to_string(),
+            forbid_account_creation: false,
+            require_uniform_fee_policy: false,
+            tag_changes: false,
+            trace_rounding: false,
+            reserved_addresses: BTreeSet::new(),
+            tx_exempt_accounts: BTreeSet::new(),
+            remainder_recipient: None,
+            audit_mutations: false,
+            max_effective_fee_rate: None,
+            secondary_burn_rate: None,
+            output_order: OutputOrder::Address,
+            fee_inclusion: FeeInclusion::Exclusive,
+            max_distinct_denoms: None,
+            max_recipients: None,
+            allow_rebates: false,
+            prove_conservation: false,
+        }
+    }
+}
+
+// Builds options from `CalculationOptions::default()`, one setting at a time, since they cannot
+// be built as a struct expression outside this crate. Each method sets the field of the same
+// name; the ones taking an address add it to its set.
+impl CalculationOptions {
+    pub fn with_fee(mut self, fee: Fee) -> Self {
+        self.fee = Some(fee);
+        self
+    }
+
+    pub fn with_fee_collector(mut self, address: &str) -> Self {
+        self.fee_collector = address.to_string();
+        self
+    }
+
+    pub fn with_forbid_account_creation(mut self, forbid: bool) -> Self {
+        self.forbid_account_creation = forbid;
+        self
+    }
+
+    pub fn with_require_uniform_fee_policy(mut self, require: bool) -> Self {
+        self.require_uniform_fee_policy = require;
+        self
+    }
+
+    pub fn with_tag_changes(mut self, tag: bool) -> Self {
+        self.tag_changes = tag;
+        self
+    }
+
+    pub fn with_trace_rounding(mut self, trace: bool) -> Self {
+        self.trace_rounding = trace;
+        self
+    }
+
+    pub fn with_reserved_address(mut self, address: &str) -> Self {
+        self.reserved_addresses.insert(address.to_string());
+        self
+    }
+
+    pub fn with_tx_exempt_account(mut self, address: &str) -> Self {
+        self.tx_exempt_accounts.insert(address.to_string());
+        self
+    }
+
+    pub fn with_remainder_recipient(mut self, address: &str) -> Self {
+        self.remainder_recipient = Some(address.to_string());
+        self
+    }
+
+    pub fn with_audit_mutations(mut self, audit: bool) -> Self {
+        self.audit_mutations = audit;
+        self
+    }
+
+    pub fn with_max_effective_fee_rate(mut self, rate: f64) -> Self {
+        self.max_effective_fee_rate = Some(rate);
+        self
+    }
+
+    pub fn with_secondary_burn_rate(mut self, rate: f64) -> Self {
+        self.secondary_burn_rate = Some(rate);
+        self
+    }
+
+    pub fn with_output_order(mut self, order: OutputOrder) -> Self {
+        self.output_order = order;
+        self
+    }
+
+    pub fn with_fee_inclusion(mut self, inclusion: FeeInclusion) -> Self {
+        self.fee_inclusion = inclusion;
+        self
+    }
+
+    pub fn with_max_distinct_denoms(mut self, limit: usize) -> Self {
+        self.max_distinct_denoms = Some(limit);
+        self
+    }
+
+    pub fn with_max_recipients(mut self, max: usize) -> Self {
+        self.max_recipients = Some(max);
+        self
+    }
+
+    pub fn with_allow_rebates(mut self, allow: bool) -> Self {
+        self.allow_rebates = allow;
+        self
+    }
+
+    pub fn with_prove_conservation(mut self, prove: bool) -> Self {
+        self.prove_conservation = prove;
+        self
+    }
+}
+
+// Same as `calculate_balance_changes`, applying `options` and returning the full
+// `BalanceChanges`.
+pub fn calculate_with_options(
+    original_balances: Vec<Balance>,
+    definitions: Vec<DenomDefinition>,
+    multi_send_tx: MultiSend,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    calculate_with_options_ref(&original_balances, &definitions, &multi_send_tx, options)
+}
+
+// Same as `calculate_with_options`, but borrows its inputs like `calculate_balance_changes_ref`.
+pub fn calculate_with_options_ref(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    if let Some(limit) = options.max_distinct_denoms {
+        check_distinct_denoms(multi_send_tx, limit)?;
+    }
+    calculate_tx(
+        original_balances,
+        &definition_map(definitions),
+        multi_send_tx,
+        options,
+    )
+}
+
+// The stages of `calculate_with_options_ref` once the denoms are counted and the definitions
+// indexed, shared with `calculate_batch`.
+fn calculate_tx<B: BalanceSource + ?Sized>(
+    original_balances: &B,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    multi_send_tx: &MultiSend,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    let tx = normalize_with_options(multi_send_tx, definitions, options)?;
+    calculate_normalized(original_balances, definitions, &tx, options)
+}
+
+fn normalize_with_options(
+    multi_send_tx: &MultiSend,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    options: &CalculationOptions,
+) -> Result<NormalizedTx, CalculationError> {
+    let mut tx = normalize(multi_send_tx, definitions)?;
+    if let Some(remainder_recipient) = &options.remainder_recipient {
+        add_remainder_outputs(&mut tx, remainder_recipient)?;
+    }
+    Ok(tx)
+}
+
+// What `check_tx` learns about a transaction without looking at balances.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CheckInfo {
+    // `BalanceChanges::cost_estimate` of the transaction, from its charges before small-balance
+    // rebates.
+    pub gas_estimate: u64,
+    // The burns plus the commissions the transaction pays, for ordering pending transactions by
+    // the fees they generate.
+    pub priority: u128,
+}
+
+// Runs the checks of `calculate_with_options_ref` that need no balances: the structure of the
+// transaction, its denoms, the limits and policies of `options` and the fee rates. They are the
+// first stages of the full calculation, unchanged, so every transaction `deliver_tx` accepts
+// passes `check_tx`, and a transaction `check_tx` rejects is rejected by `deliver_tx` with the
+// same error. Signers are not checked: a `MultiSend` carries none, so every input counts as
+// signed by its address.
+pub fn check_tx(
+    multi_send_tx: &MultiSend,
+    definitions: &[DenomDefinition],
+    options: &CalculationOptions,
+) -> Result<CheckInfo, CalculationError> {
+    if let Some(limit) = options.max_distinct_denoms {
+        check_distinct_denoms(multi_send_tx, limit)?;
+    }
+    let definitions = definition_map(definitions);
+    let tx = normalize_with_options(multi_send_tx, &definitions, options)?;
+    let (aggregates, plan) = plan_normalized(&definitions, &tx, options)?;
+    let priority = plan.charges.iter().fold(0u128, |priority, charge| {
+        priority
+            .saturating_add(charge.burn.max(0) as u128)
+            .saturating_add(charge.commission.max(0) as u128)
+    });
+    Ok(CheckInfo {
+        gas_estimate: estimate_cost(&tx, &aggregates, &plan),
+        priority,
+    })
+}
+
+// Executes a transaction against the current balances, running every check of `check_tx` again
+// followed by those needing balances. The same as `calculate_with_options_ref`, named as the
+// counterpart of `check_tx`.
+pub fn deliver_tx(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    calculate_with_options_ref(original_balances, definitions, multi_send_tx, options)
+}
+
+// Rejects `multi_send_tx` if it touches more than `limit` distinct denoms. The coins are
+// counted as they are read and counting stops at the first denom over the limit, so rejecting a
+// transaction takes time linear in its coins and memory linear in `limit` only. Every entry
+// point taking options counts before building any per-denom structure.
+pub(crate) fn check_distinct_denoms(
+    multi_send_tx: &MultiSend,
+    limit: usize,
+) -> Result<(), CalculationError> {
+    let mut denoms: BTreeSet<&str> = BTreeSet::new();
+    let coins = multi_send_tx
+        .inputs
+        .iter()
+        .chain(&multi_send_tx.outputs)
+        .flat_map(|balance| &balance.coins);
+    for coin in coins {
+        if denoms.insert(&coin.denom) && denoms.len() > limit {
+            return Err(CalculationError::TooManyDenoms {
+                limit,
+                counted: denoms.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Appends an output to `recipient` for every denom whose inputs exceed its outputs. Denoms whose
+// outputs exceed their inputs are left for `aggregate` to reject.
+fn add_remainder_outputs(tx: &mut NormalizedTx, recipient: &str) -> Result<(), CalculationError> {
+    let mut residuals: BTreeMap<&str, i128> = BTreeMap::new();
+    for (legs, sign) in [(&tx.inputs, 1), (&tx.outputs, -1)] {
+        for leg in legs {
+            let residual = residuals.entry(&leg.denom).or_default();
+            *residual = leg
+                .amount
+                .checked_mul(sign)
+                .and_then(|amount| residual.checked_add(amount))
+                .ok_or_else(|| overflow(&leg.denom))?;
+        }
+    }
+    let remainders: Vec<Leg> = residuals
+        .into_iter()
+        .filter(|(_, residual)| *residual > 0)
+        .map(|(denom, residual)| Leg {
+            address: recipient.to_string(),
+            denom: denom.to_string(),
+            amount: residual,
+        })
+        .collect();
+    tx.outputs.extend(remainders);
+    Ok(())
+}
+
+// Same as `calculate_balance_changes`, but reads the legs of the transaction from iterators that
+// are consumed once, e.g. straight from a decoder, instead of from a `MultiSend`. Outputs are
+// merged into one leg per recipient and denom as they are read, which changes nothing since no
+// fees are charged on the receiving side. This does not lower peak memory below that of a
+// `MultiSend`: input legs are kept one by one, as the burn and commission of each are rounded
+// against the totals of all of them, and the legs then go through the same stages as those of a
+// `MultiSend`.
+pub fn calculate_balance_changes_from_legs(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    inputs: impl IntoIterator<Item = Balance>,
+    outputs: impl IntoIterator<Item = Balance>,
+) -> Result<Vec<Balance>, CalculationError> {
+    let balance_changes = calculate_from_legs_with_options(
+        original_balances,
+        definitions,
+        inputs,
+        outputs,
+        &CalculationOptions::default(),
+    )?;
+    Ok(balance_changes.changes)
+}
+
+// Same as `calculate_balance_changes_from_legs`, applying `options` and returning the full
+// `BalanceChanges`. The distinct denoms are counted as the legs are read, so a transaction over
+// `options.max_distinct_denoms` is rejected at its first coin over the limit.
+pub fn calculate_from_legs_with_options(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    inputs: impl IntoIterator<Item = Balance>,
+    outputs: impl IntoIterator<Item = Balance>,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    let definitions = definition_map(definitions);
+    let mut denoms: BTreeSet<String> = BTreeSet::new();
+    let mut check_coin = |coin: &Coin| -> Result<(), CalculationError> {
+        if let Some(limit) = options.max_distinct_denoms {
+            if !denoms.contains(&coin.denom) {
+                denoms.insert(coin.denom.clone());
+                if denoms.len() > limit {
+                    return Err(CalculationError::TooManyDenoms {
+                        limit,
+                        counted: denoms.len(),
+                    });
+                }
+            }
+        }
+        check_leg_coin(coin, &definitions)
+    };
+
+    let mut input_legs: Vec<Leg> = Vec::new();
+    for balance in inputs {
+        for coin in balance.coins {
+            check_coin(&coin)?;
+            input_legs.push(Leg {
+                address: balance.address.clone(),
+                denom: coin.denom,
+                amount: coin.amount,
+            });
+        }
+    }
+
+    let mut output_legs = MergedOutputs::default();
+    for balance in outputs {
+        for coin in balance.coins {
+            check_coin(&coin)?;
+            output_legs.push(Leg {
+                address: balance.address.clone(),
+                denom: coin.denom,
+                amount: coin.amount,
+            })?;
+        }
+    }
+
+    let mut tx = NormalizedTx {
+        inputs: input_legs,
+        outputs: output_legs.legs,
+    };
+    if let Some(remainder_recipient) = &options.remainder_recipient {
+        add_remainder_outputs(&mut tx, remainder_recipient)?;
+    }
+    calculate_normalized(original_balances, &definitions, &tx, options)
+}
+
+// The stages following `normalize`, shared by the entry points.
+fn calculate_normalized<B: BalanceSource + ?Sized>(
+    original_balances: &B,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    tx: &NormalizedTx,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    let (aggregates, mut plan) = plan_normalized(definitions, tx, options)?;
+    plan_small_balance_rebates(&mut plan, original_balances, definitions)?;
+    apply_plan(original_balances, tx, &aggregates, plan, options)
+}
+
+// The stages of `calculate_normalized` that do not look at balances, which are all `check_tx`
+// runs.
+fn plan_normalized(
+    definitions: &BTreeMap<String, DenomDefinition>,
+    tx: &NormalizedTx,
+    options: &CalculationOptions,
+) -> Result<(DenomAggregates, ChargePlan), CalculationError> {
+    check_reserved_addresses(tx, options)?;
+    check_recipient_count(tx, options)?;
+    let flat_tx = FlatTx::new(tx);
+    let aggregates = aggregate_flat(&flat_tx, definitions, options)?;
+    check_policies(&aggregates, definitions, options)?;
+    let plan = plan_charges_flat(&flat_tx, &aggregates, definitions, options)?;
+    check_effective_fee_rates(&plan, options)?;
+    Ok((aggregates, plan))
+}
+
+// The last stages of a calculation, once the charges of a valid transaction are planned.
+fn apply_plan<B: BalanceSource + ?Sized>(
+    original_balances: &B,
+    tx: &NormalizedTx,
+    aggregates: &DenomAggregates,
+    plan: ChargePlan,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    let inclusive_tx;
+    let (tx, plan) = match options.fee_inclusion {
+        FeeInclusion::Exclusive => (tx, plan),
+        FeeInclusion::Inclusive => {
+            let (adjusted_tx, adjusted_plan) = include_fees(tx, plan)?;
+            inclusive_tx = adjusted_tx;
+            (&inclusive_tx, adjusted_plan)
+        }
+    };
+    let mut balance_changes = apply_charges(original_balances, tx, &plan, options)?;
+    if options.output_order == OutputOrder::FirstAppearance {
+        order_by_first_appearance(&mut balance_changes.changes, tx, &plan);
+    }
+    balance_changes.cost_estimate = estimate_cost(tx, aggregates, &plan);
+    if options.tag_changes {
+        balance_changes.tagged_changes = tag_changes(tx, &plan, options)?;
+    }
+    balance_changes.report = transfer_report(tx, &plan)?;
+    if options.prove_conservation {
+        balance_changes.conservation_proof = Some(conservation_proof(
+            &balance_changes.changes,
+            &tag_changes(tx, &plan, options)?,
+            &plan,
+        )?);
+    }
+    if options.trace_rounding {
+        balance_changes.rounding_trace = plan.rounding_trace;
+    }
+    Ok(balance_changes)
+}
+
+// Moves the fees of `plan` from on top of the inputs to out of the outputs, as
+// `FeeInclusion::Inclusive` describes: every charge's principal shrinks by its fees, and so do
+// the outputs of its denom.
+fn include_fees(
+    tx: &NormalizedTx,
+    mut plan: ChargePlan,
+) -> Result<(NormalizedTx, ChargePlan), CalculationError> {
+    let mut fees: BTreeMap<String, i128> = BTreeMap::new();
+    for charge in &mut plan.charges {
+        let fee = charge.fees()?;
+        accumulate(
+            fees.entry(charge.denom.clone()).or_default(),
+            fee,
+            &charge.denom,
+        )?;
+        charge.amount = charge
+            .amount
+            .checked_sub(fee)
+            .ok_or_else(|| overflow(&charge.denom))?;
+    }
+
+    let mut tx = tx.clone();
+    for (denom, fee) in fees {
+        // without a fee there is nothing to take, and the outputs may well total zero
+        if fee == 0 {
+            continue;
+        }
+        let outputs: Vec<&mut Leg> = tx
+            .outputs
+            .iter_mut()
+            .filter(|leg| leg.denom == denom)
+            .collect();
+        let mut total: i128 = 0;
+        for leg in &outputs {
+            accumulate(&mut total, leg.amount, &denom)?;
+        }
+        if fee > total {
+            return Err(CalculationError::FeesExceedOutputs {
+                denom,
+                fees: fee,
+                outputs: total,
+            });
+        }
+        let mut remaining = fee;
+        let mut shares = Vec::with_capacity(outputs.len());
+        for leg in &outputs {
+            // rounded down, so that a rebate's share leaves a remainder that is never negative
+            let share = fee
+                .checked_mul(leg.amount)
+                .ok_or_else(|| overflow(&denom))?
+                .div_euclid(total);
+            shares.push(share);
+            remaining = remaining
+                .checked_sub(share)
+                .ok_or_else(|| overflow(&denom))?;
+        }
+        for (leg, share) in outputs.into_iter().zip(shares) {
+            // at most one token is left per output, and only where the share was rounded down
+            let extra = i128::from(remaining > 0 && leg.amount > share);
+            remaining -= extra;
+            leg.amount = share
+                .checked_add(extra)
+                .and_then(|taken| leg.amount.checked_sub(taken))
+                .ok_or_else(|| overflow(&denom))?;
+        }
+    }
+    Ok((tx, plan))
+}
+
+// Reorders `changes`, sorted by address, as `OutputOrder::FirstAppearance` describes.
+fn order_by_first_appearance(changes: &mut [Balance], tx: &NormalizedTx, plan: &ChargePlan) {
+    let mut positions: BTreeMap<&str, usize> = BTreeMap::new();
+    let appearances = tx
+        .inputs
+        .iter()
+        .chain(&tx.outputs)
+        .map(|leg| leg.address.as_str())
+        .chain(plan.charges.iter().map(|charge| charge.issuer.as_str()));
+    for address in appearances {
+        let next = positions.len();
+        positions.entry(address).or_insert(next);
+    }
+    // the sort is stable, so the accounts that do not appear stay in address order
+    changes.sort_by_key(|change| {
+        positions
+            .get(change.address.as_str())
+            .copied()
+            .unwrap_or(usize::MAX)
+    });
+}
+
+// First phase of a preview-then-commit flow: runs every validation and fee computation of
+// `calculate_balance_changes` up front, so that committing the result cannot fail.
+pub fn prepare(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+) -> Result<PreparedTx, CalculationError> {
+    prepare_with_options(
+        original_balances,
+        definitions,
+        multi_send_tx,
+        &CalculationOptions::default(),
+    )
+}
+
+// Same as `prepare`, validating and computing as `calculate_with_options_ref` does.
+pub fn prepare_with_options(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    options: &CalculationOptions,
+) -> Result<PreparedTx, CalculationError> {
+    let balance_changes =
+        calculate_with_options_ref(original_balances, definitions, multi_send_tx, options)?;
+    Ok(PreparedTx { balance_changes })
+}
+
+// A validated transaction whose changes have been computed but not handed out yet.
+#[derive(Debug, Clone)]
+pub struct PreparedTx {
+    pub(crate) balance_changes: BalanceChanges,
+}
+
+impl PreparedTx {
+    // The changes committing the transaction will return, for previewing.
+    pub fn preview(&self) -> &[Balance] {
+        &self.balance_changes.changes
+    }
+
+    pub fn commit(self) -> Vec<Balance> {
+        self.balance_changes.changes
+    }
+}
+
+// The aggregate and leg count of every denom an edit touches, by denom, as they are once the
+// edit is applied.
+type Recount = BTreeMap<String, (DenomAggregate, usize)>;
+
+// Keeps the calculation of a draft transaction up to date while its legs are edited one at a
+// time, e.g. for a live preview. An edit only adjusts the aggregates and re-plans the charges,
+// and their rounding traces, of the denoms it touches. Every edit returns the same result as calculating the edited
+// transaction from scratch with the calculator's options, errors included: a draft whose inputs
+// and outputs do not match yet is reported as such. The one option a draft does not apply is
+// `remainder_recipient`, as its unmatched legs are reported instead.
+pub struct IncrementalCalculator {
+    pub(crate) original_balances: Vec<Balance>,
+    pub(crate) definitions: BTreeMap<String, DenomDefinition>,
+    pub(crate) options: CalculationOptions,
+    pub(crate) tx: NormalizedTx,
+    pub(crate) aggregates: DenomAggregates,
+    // The number of legs of every denom in `tx`, to drop a denom's aggregate with its last leg.
+    pub(crate) leg_counts: BTreeMap<String, usize>,
+    pub(crate) plan: ChargePlan,
+    // How the charge of every input was rounded, one trace per input of `tx`, when the options
+    // trace the rounding. `plan.rounding_trace` is left empty, as it is assembled from these.
+    pub(crate) input_traces: Vec<RoundingTrace>,
+}
+
+impl IncrementalCalculator {
+    // Starts from an empty transaction.
+    pub fn new(original_balances: Vec<Balance>, definitions: Vec<DenomDefinition>) -> Self {
+        Self::with_options(
+            original_balances,
+            definitions,
+            CalculationOptions::default(),
+        )
+    }
+
+    // Same as `new`, calculating with `options`. An edit that would take the draft over
+    // `options.max_distinct_denoms` is rejected before the aggregate of the new denom is stored.
+    pub fn with_options(
+        original_balances: Vec<Balance>,
+        definitions: Vec<DenomDefinition>,
+        options: CalculationOptions,
+    ) -> Self {
+        IncrementalCalculator {
+            original_balances,
+            definitions: definition_map(&definitions),
+            options,
+            tx: NormalizedTx {
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+            },
+            aggregates: DenomAggregates::default(),
+            leg_counts: BTreeMap::new(),
+            plan: ChargePlan {
+                charges: Vec::new(),
+                rounding_trace: RoundingTrace::default(),
+            },
+            input_traces: Vec::new(),
+        }
+    }
+
+    // The draft transaction, one balance per leg.
+    pub fn multi_send(&self) -> MultiSend {
+        let balances = |legs: &[Leg]| {
+            legs.iter()
+                .map(|leg| Balance {
+                    address: leg.address.clone(),
+                    coins: vec![Coin {
+                        denom: leg.denom.clone(),
+                        amount: leg.amount,
+                    }],
+                })
+                .collect()
+        };
+        MultiSend {
+            inputs: balances(&self.tx.inputs),
+            outputs: balances(&self.tx.outputs),
+        }
+    }
+
+    // The edits below reject coins of undefined denoms, negative coins, coins that would overflow
+    // the sums or charges of their denom, and coins of a denom over the limit, leaving the draft
+    // unchanged. Indexes refer to legs in the order they were added and panic when out of bounds,
+    // like `Vec`.
+
+    pub fn add_input(
+        &mut self,
+        address: &str,
+        coin: Coin,
+    ) -> Result<BalanceChanges, CalculationError> {
+        let leg = self.leg(address, coin)?;
+        let mut recount = Recount::new();
+        self.count(&mut recount, &leg, true, 1)?;
+        let charges = self.replan(&recount, None)?;
+        let (charge, trace) = self.charge(&recount, &leg)?;
+        self.commit(recount, charges);
+        self.plan.charges.push(charge);
+        self.input_traces.push(trace);
+        self.tx.inputs.push(leg);
+        self.changes()
+    }
+
+    // Replaces the coin of the input at `index`, keeping its address.
+    pub fn update_input(
+        &mut self,
+        index: usize,
+        coin: Coin,
+    ) -> Result<BalanceChanges, CalculationError> {
+        let leg = self.leg(&self.tx.inputs[index].address.clone(), coin)?;
+        let mut recount = Recount::new();
+        self.count(&mut recount, &self.tx.inputs[index], true, -1)?;
+        self.count(&mut recount, &leg, true, 1)?;
+        let mut charges = self.replan(&recount, Some(index))?;
+        let (charge, trace) = self.charge(&recount, &leg)?;
+        charges.push((index, charge, trace));
+        self.commit(recount, charges);
+        self.tx.inputs[index] = leg;
+        self.changes()
+    }
+
+    pub fn remove_input(&mut self, index: usize) -> Result<BalanceChanges, CalculationError> {
+        let mut recount = Recount::new();
+        self.count(&mut recount, &self.tx.inputs[index], true, -1)?;
+        let charges = self.replan(&recount, Some(index))?;
+        self.commit(recount, charges);
+        self.tx.inputs.remove(index);
+        self.plan.charges.remove(index);
+        self.input_traces.remove(index);
+        self.changes()
+    }
+
+    pub fn add_output(
+        &mut self,
+        address: &str,
+        coin: Coin,
+    ) -> Result<BalanceChanges, CalculationError> {
+        let leg = self.leg(address, coin)?;
+        let mut recount = Recount::new();
+        self.count(&mut recount, &leg, false, 1)?;
+        let charges = self.replan(&recount, None)?;
+        self.commit(recount, charges);
+        self.tx.outputs.push(leg);
+        self.changes()
+    }
+
+    // Replaces the coin of the output at `index`, keeping its address.
+    pub fn update_output(
+        &mut self,
+        index: usize,
+        coin: Coin,
+    ) -> Result<BalanceChanges, CalculationError> {
+        let leg = self.leg(&self.tx.outputs[index].address.clone(), coin)?;
+        let mut recount = Recount::new();
+        self.count(&mut recount, &self.tx.outputs[index], false, -1)?;
+        self.count(&mut recount, &leg, false, 1)?;
+        let charges = self.replan(&recount, None)?;
+        self.commit(recount, charges);
+        self.tx.outputs[index] = leg;
+        self.changes()
+    }
+
+    pub fn remove_output(&mut self, index: usize) -> Result<BalanceChanges, CalculationError> {
+        let mut recount = Recount::new();
+        self.count(&mut recount, &self.tx.outputs[index], false, -1)?;
+        let charges = self.replan(&recount, None)?;
+        self.commit(recount, charges);
+        self.tx.outputs.remove(index);
+        self.changes()
+    }
+
+    fn leg(&self, address: &str, coin: Coin) -> Result<Leg, CalculationError> {
+        check_leg_coin(&coin, &self.definitions)?;
+        Ok(Leg {
+            address: address.to_string(),
+            denom: coin.denom,
+            amount: coin.amount,
+        })
+    }
+
+    // Adds (`sign` 1) or removes (`sign` -1) `leg` to or from the aggregate and leg count of its
+    // denom in `recount`, which starts from those of the draft.
+    fn count(
+        &self,
+        recount: &mut Recount,
+        leg: &Leg,
+        is_input: bool,
+        sign: i128,
+    ) -> Result<(), CalculationError> {
+        let (aggregate, leg_count) = recount.entry(leg.denom.clone()).or_insert_with(|| {
+            (
+                self.aggregates
+                    .denoms
+                    .get(&leg.denom)
+                    .cloned()
+                    .unwrap_or_default(),
+                self.leg_counts.get(&leg.denom).copied().unwrap_or(0),
+            )
+        });
+        let is_exempt = is_exempt(&self.definitions[&leg.denom], &leg.address, &self.options);
+        let amount = leg
+            .amount
+            .checked_mul(sign)
+            .ok_or_else(|| overflow(&leg.denom))?;
+        if is_input {
+            aggregate.add_input(&leg.denom, amount, is_exempt)?;
+        } else {
+            aggregate.add_output(&leg.denom, amount, is_exempt)?;
+        }
+        if sign > 0 {
+            *leg_count += 1;
+        } else {
+            *leg_count -= 1;
+        }
+
+        if let Some(limit) = self.options.max_distinct_denoms {
+            // a denom is only dropped once its last leg is counted out, so every recounted denom
+            // without legs was in the draft
+            let added = recount
+                .iter()
+                .filter(|(denom, (_, leg_count))| {
+                    *leg_count > 0 && !self.leg_counts.contains_key(*denom)
+                })
+                .count();
+            let dropped = recount
+                .values()
+                .filter(|(_, leg_count)| *leg_count == 0)
+                .count();
+            let counted = self.leg_counts.len() + added - dropped;
+            if counted > limit {
+                return Err(CalculationError::TooManyDenoms { limit, counted });
+            }
+        }
+        Ok(())
+    }
+
+    // The charge of the input `leg` under the aggregates of `recount`, and how it was rounded.
+    fn charge(
+        &self,
+        recount: &Recount,
+        leg: &Leg,
+    ) -> Result<(Charge, RoundingTrace), CalculationError> {
+        let definition = &self.definitions[&leg.denom];
+        let mut trace = RoundingTrace::default();
+        let charge = leg_charge(
+            &leg.address,
+            leg.amount,
+            definition,
+            &recount[&leg.denom].0,
+            is_exempt(definition, &leg.address, &self.options),
+            self.options.secondary_burn_rate.unwrap_or(0.0),
+            self.options.trace_rounding.then_some(&mut trace),
+        )?;
+        Ok((charge, trace))
+    }
+
+    // The new charges, by index, of the inputs whose denoms were recounted, except the input at
+    // `skipped`.
+    fn replan(
+        &self,
+        recount: &Recount,
+        skipped: Option<usize>,
+    ) -> Result<Vec<(usize, Charge, RoundingTrace)>, CalculationError> {
+        let mut charges = Vec::new();
+        for (index, leg) in self.tx.inputs.iter().enumerate() {
+            if Some(index) != skipped && recount.contains_key(&leg.denom) {
+                let (charge, trace) = self.charge(recount, leg)?;
+                charges.push((index, charge, trace));
+            }
+        }
+        Ok(charges)
+    }
+
+    // Stores the recounted aggregates, dropping those of denoms left without legs, and the new
+    // charges. Nothing here can fail, so an edit only changes the draft once it is accepted.
+    fn commit(&mut self, recount: Recount, charges: Vec<(usize, Charge, RoundingTrace)>) {
+        for (denom, (aggregate, leg_count)) in recount {
+            if leg_count == 0 {
+                self.aggregates.denoms.remove(&denom);
+                self.leg_counts.remove(&denom);
+            } else {
+                self.aggregates.denoms.insert(denom.clone(), aggregate);
+                self.leg_counts.insert(denom, leg_count);
+            }
+        }
+        record_denoms(self.aggregates.denoms.len());
+        for (index, charge, trace) in charges {
+            self.plan.charges[index] = charge;
+            self.input_traces[index] = trace;
+        }
+    }
+
+    fn changes(&self) -> Result<BalanceChanges, CalculationError> {
+        check_reserved_addresses(&self.tx, &self.options)?;
+        check_recipient_count(&self.tx, &self.options)?;
+        self.aggregates.check_matched()?;
+        check_policies(&self.aggregates, &self.definitions, &self.options)?;
+        // the draft keeps every output leg to be edited by index, while a calculation from
+        // scratch merges them per recipient and denom
+        let mut outputs = MergedOutputs::default();
+        for leg in &self.tx.outputs {
+            outputs.push(leg.clone())?;
+        }
+        let tx = NormalizedTx {
+            inputs: self.tx.inputs.clone(),
+            outputs: outputs.legs,
+        };
+        check_effective_fee_rates(&self.plan, &self.options)?;
+        let mut plan = self.plan.clone();
+        plan.rounding_trace.roundings = self
+            .input_traces
+            .iter()
+            .flat_map(|trace| trace.roundings.iter().cloned())
+            .collect();
+        plan_small_balance_rebates(
+            &mut plan,
+            self.original_balances.as_slice(),
+            &self.definitions,
+        )?;
+        apply_plan(
+            self.original_balances.as_slice(),
+            &tx,
+            &self.aggregates,
+            plan,
+            &self.options,
+        )
+    }
+}
+
+// Whether `multi_send_tx` is valid but changes nothing: no account's balance changes and
+// nothing is burned or paid in commissions, e.g. an account sending a fee-free denom to itself.
+// A transfer charged a burn or commission is never a no-op. Rejected transactions are errors.
+pub fn is_noop(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+) -> Result<bool, CalculationError> {
+    let balance_changes = calculate_with_options_ref(
+        original_balances,
+        definitions,
+        multi_send_tx,
+        &CalculationOptions::default(),
+    )?;
+    let charged = balance_changes
+        .report
+        .denoms
+        .values()
+        .any(|denom_report| denom_report.burned != 0 || denom_report.commission != 0);
+    Ok(balance_changes.changes.is_empty() && !charged)
+}
+
+// Computes the burn, commission and transfer deltas of `multi_send_tx` without looking at any
+// balances, i.e. as if every sender could cover its inputs plus fees. Useful to estimate fees
+// before funding the senders; accounts whose net change is zero are left out. The rates are
+// validated as for a checked calculation.
+pub fn calculate_deltas_unchecked(
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+) -> Result<Vec<Balance>, CalculationError> {
+    let definitions = definition_map(definitions);
+    let tx = normalize(multi_send_tx, &definitions)?;
+    let options = CalculationOptions::default();
+    let flat_tx = FlatTx::new(&tx);
+    let aggregates = aggregate_flat(&flat_tx, &definitions, &options)?;
+    check_policies(&aggregates, &definitions, &options)?;
+    let plan = plan_charges_flat(&flat_tx, &aggregates, &definitions, &options)?;
+    Ok(net_charges(&tx, &plan)?.changes)
+}
+
+// An operation of a batch processed by `calculate_batch`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum BatchOp {
+    Transfer(MultiSend),
+    // Defines the denom, or replaces its definition, for the rest of the batch. Updating a
+    // disabled denom enables it again.
+    UpdateDenom(DenomDefinition),
+    // Removes the definition of the denom for the rest of the batch, so that transfers of it are
+    // rejected as undefined.
+    DisableDenom(String),
+}
+
+// Processes `ops` in order, each transfer seeing the balances left by the transfers accepted
+// before it and the definitions as updated by the operations before it. Returns the outcome of
+// every transfer, in order; a rejected transfer leaves the balances unchanged and does not stop
+// the batch. The balances are merged once into a working snapshot, which every accepted transfer
+// then updates in place.
+pub fn calculate_batch(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    ops: &[BatchOp],
+    options: &CalculationOptions,
+) -> Vec<Result<BalanceChanges, CalculationError>> {
+    let merged = || -> Result<BTreeMap<String, CoinSet>, CalculationError> {
+        let mut balances: BTreeMap<String, CoinSet> = BTreeMap::new();
+        for balance in original_balances {
+            let coins = balances.entry(balance.address.clone()).or_default();
+            *coins = coins.checked_add(&CoinSet::from_coins(&balance.coins)?)?;
+        }
+        Ok(balances)
+    };
+    let mut balances = match merged() {
+        Ok(balances) => balances,
+        // original balances that cannot be summed reject every transfer
+        Err(e) => {
+            return ops
+                .iter()
+                .filter(|op| matches!(op, BatchOp::Transfer(_)))
+                .map(|_| Err(e.clone()))
+                .collect()
+        }
+    };
+    let mut definitions = definition_map(definitions);
+    let mut results = Vec::new();
+    for op in ops {
+        match op {
+            BatchOp::Transfer(multi_send_tx) => {
+                let result = options
+                    .max_distinct_denoms
+                    .map_or(Ok(()), |limit| check_distinct_denoms(multi_send_tx, limit))
+                    .and_then(|()| calculate_tx(&balances, &definitions, multi_send_tx, options))
+                    .and_then(|balance_changes| {
+                        apply_changes(&mut balances, &balance_changes.changes)?;
+                        Ok(balance_changes)
+                    });
+                results.push(result);
+            }
+            BatchOp::UpdateDenom(definition) => {
+                definitions.insert(definition.denom.clone(), definition.clone());
+            }
+            BatchOp::DisableDenom(denom) => {
+                definitions.remove(denom);
+            }
+        }
+    }
+    results
+}
+
+// The outcome of a batch processed by `calculate_batch_logged`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BatchResult {
+    // The outcome of every transfer, in order, as `calculate_batch` returns them.
+    pub results: Vec<Result<BalanceChanges, CalculationError>>,
+    pub rejections: RejectionLog,
+}
+
+impl BatchResult {
+    // One line for the end of a batch run, e.g.
+    //   "40 transfers, 1 accepted, 39 rejected (37 insufficient_balance, 2 unknown_denom)"
+    pub fn summary(&self) -> String {
+        let rejected = self.rejections.rejections.len();
+        let mut summary = format!(
+            "{} transfers, {} accepted, {} rejected",
+            self.results.len(),
+            self.results.len() - rejected,
+            rejected
+        );
+        if rejected != 0 {
+            let categories: Vec<String> = self
+                .rejections
+                .summary()
+                .iter()
+                .map(|(category, count)| format!("{} {}", count, category))
+                .collect();
+            summary.push_str(&format!(" ({})", categories.join(", ")));
+        }
+        summary
+    }
+}
+
+// The rejected transfers of a batch, in order.
+#[derive(Debug, Clone, Default)]
+pub struct RejectionLog {
+    pub rejections: Vec<Rejection>,
+}
+
+impl RejectionLog {
+    // The number of rejections of every category of error.
+    pub fn summary(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for rejection in &self.rejections {
+            *counts.entry(rejection.category).or_default() += 1;
+        }
+        counts
+    }
+}
+
+// A transfer of a batch and why it was rejected.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Rejection {
+    // The position of the transfer among the operations of the batch.
+    pub index: usize,
+    // `CalculationError::category` of `error`.
+    pub category: &'static str,
+    // The account and the denom the error is about, where it names them.
+    pub address: Option<String>,
+    pub denom: Option<String>,
+    pub error: CalculationError,
+    pub tx: MultiSend,
+}
+
+// Same as `calculate_batch`, but also collects the rejected transfers into a `RejectionLog`.
+pub fn calculate_batch_logged(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    ops: &[BatchOp],
+    options: &CalculationOptions,
+) -> BatchResult {
+    let results = calculate_batch(original_balances, definitions, ops, options);
+    let transfers = ops.iter().enumerate().filter_map(|(index, op)| match op {
+        BatchOp::Transfer(multi_send_tx) => Some((index, multi_send_tx)),
+        _ => None,
+    });
+    let rejections = transfers
+        .zip(&results)
+        .filter_map(|((index, multi_send_tx), result)| {
+            let error = result.as_ref().err()?;
+            Some(Rejection {
+                index,
+                category: error.category(),
+                address: error.address().map(str::to_string),
+                denom: error.denom().map(str::to_string),
+                error: error.clone(),
+                tx: multi_send_tx.clone(),
+            })
+        })
+        .collect();
+    BatchResult {
+        results,
+        rejections: RejectionLog { rejections },
+    }
+}
+
+// Adds `changes` to `balances`, or leaves them unchanged if a change does not fit.
+fn apply_changes(
+    balances: &mut BTreeMap<String, CoinSet>,
+    changes: &[Balance],
+) -> Result<(), CalculationError> {
+    let mut updated = Vec::with_capacity(changes.len());
+    for change in changes {
+        let current = balances.get(&change.address).cloned().unwrap_or_default();
+        let delta = CoinSet::from_coins(&change.coins)?;
+        updated.push((change.address.clone(), current.checked_add(&delta)?));
+    }
+    balances.extend(updated);
+    Ok(())
+}
+
+// Replacement rates for some denoms; the other denoms keep the rates of their definitions.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct RateOverrides {
+    pub burn_rates: BTreeMap<String, f64>,
+    pub commission_rates: BTreeMap<String, f64>,
+}
+
+impl RateOverrides {
+    pub fn new() -> Self {
+        RateOverrides::default()
+    }
+
+    pub fn with_burn_rate(mut self, denom: &str, rate: f64) -> Self {
+        self.burn_rates.insert(denom.to_string(), rate);
+        self
+    }
+
+    pub fn with_commission_rate(mut self, denom: &str, rate: f64) -> Self {
+        self.commission_rates.insert(denom.to_string(), rate);
+        self
+    }
+
+    fn apply(&self, definitions: &[DenomDefinition]) -> Vec<DenomDefinition> {
+        definitions
+            .iter()
+            .map(|definition| DenomDefinition {
+                burn_rate: *self
+                    .burn_rates
+                    .get(&definition.denom)
+                    .unwrap_or(&definition.burn_rate),
+                commission_rate: *self
+                    .commission_rates
+                    .get(&definition.denom)
+                    .unwrap_or(&definition.commission_rate),
+                ..definition.clone()
+            })
+            .collect()
+    }
+}
+
+// Runs `multi_send_tx` under every schedule of rates in `schedules`, applied on top of
+// `base_definitions`, and returns the report of each in the same order. Fails if the
+// transaction is rejected under any of them.
+pub fn compare_schedules(
+    original_balances: &[Balance],
+    base_definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    schedules: &[RateOverrides],
+) -> Result<Vec<TransferReport>, CalculationError> {
+    schedules
+        .iter()
+        .map(|schedule| {
+            let balance_changes = calculate_with_options_ref(
+                original_balances,
+                &schedule.apply(base_definitions),
+                multi_send_tx,
+                &CalculationOptions::default(),
+            )?;
+            Ok(balance_changes.report)
+        })
+        .collect()
+}
+
+// The highest burn plus commission a sender could be charged for sending `principal` on its own
+// when the rates may be anywhere in the given ranges. Rounded-up fees only grow with the rates,
+// so this is the fee at the upper bounds, rounded the way the calculation rounds it. Fails with
+// `AmountOverflow`, naming no denom, if the calculation would overflow on `principal`.
+pub fn max_fee_over_rate_range(
+    principal: i128,
+    burn_range: std::ops::RangeInclusive<f64>,
+    commission_range: std::ops::RangeInclusive<f64>,
+) -> Result<i128, CalculationError> {
+    let definition = DenomDefinition {
+        denom: String::new(),
+        issuer: String::new(),
+        burn_rate: *burn_range.end(),
+        commission_rate: *commission_range.end(),
+        small_balance_rebate: None,
+    };
+    let mut aggregate = DenomAggregate::default();
+    aggregate.add_input(&definition.denom, principal, false)?;
+    aggregate.add_output(&definition.denom, principal, false)?;
+    let charge = leg_charge(
+        "sender",
+        principal,
+        &definition,
+        &aggregate,
+        false,
+        0.0,
+        None,
+    )?;
+    charge.fees()
+}
+
+// The issuer of `denom`, if it is defined.
+pub fn issuer_of<'a>(definitions: &'a [DenomDefinition], denom: &str) -> Option<&'a str> {
+    definitions
+        .iter()
+        .find(|definition| definition.denom == denom)
+        .map(|definition| definition.issuer.as_str())
+}
+
+pub(crate) fn definition_map(definitions: &[DenomDefinition]) -> BTreeMap<String, DenomDefinition> {
+    let mut definition_map: BTreeMap<String, DenomDefinition> = BTreeMap::new();
+
+    for definition in definitions {
+        definition_map.insert(definition.denom.clone(), definition.clone());
+    }
+    definition_map
+}
+
+// The calculation is a pipeline of pure stages:
+//
+//   MultiSend --normalize--> NormalizedTx --aggregate--> DenomAggregates
+//             --plan_charges--> ChargePlan --apply_charges--> BalanceChanges
+//
+// Every stage only depends on the outputs of the previous ones, so each can be tested on
+// hand-built values, and the boundaries between them are where timing, tracing or explanations
+// of a calculation hook in. The calculation runs the aggregate and plan_charges stages over a
+// `FlatTx`, the interned form of a `NormalizedTx` (see `aggregate_flat` and `plan_charges_flat`).
+//
+// All maps used along the way are `BTreeMap`s rather than `HashMap`s, whose iteration order is
+// randomized per process. Processing is therefore bit-identical across runs and platforms, and
+// changes come out sorted by address and then denom.
+
+// A single coin leaving (input) or entering (output) an account.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Leg {
+    pub(crate) address: String,
+    pub(crate) denom: String,
+    pub(crate) amount: i128,
+}
+
+// A `MultiSend` flattened into single coin legs, in transaction order. Every denom of a
+// normalized transaction has a definition.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NormalizedTx {
+    pub(crate) inputs: Vec<Leg>,
+    pub(crate) outputs: Vec<Leg>,
+}
+
+// Input legs are kept one by one, as the burn and commission of each are rounded separately.
+// Output legs to the same recipient and denom are merged into a single credit, see
+// `MergedOutputs`. Coins of undefined denoms and negative coins are rejected, see
+// `check_leg_coin`.
+pub(crate) fn normalize(
+    multi_send_tx: &MultiSend,
+    definitions: &BTreeMap<String, DenomDefinition>,
+) -> Result<NormalizedTx, CalculationError> {
+    let legs = |balances: &[Balance]| -> Result<Vec<Leg>, CalculationError> {
+        let mut legs = Vec::new();
+        for balance in balances {
+            for coin in &balance.coins {
+                check_leg_coin(coin, definitions)?;
+                legs.push(Leg {
+                    address: balance.address.clone(),
+                    denom: coin.denom.clone(),
+                    amount: coin.amount,
+                });
+            }
+        }
+        Ok(legs)
+    };
+
+    let mut outputs = MergedOutputs::default();
+    for leg in legs(&multi_send_tx.outputs)? {
+        outputs.push(leg)?;
+    }
+    Ok(NormalizedTx {
+        inputs: legs(&multi_send_tx.inputs)?,
+        outputs: outputs.legs,
+    })
+}
+
+// Rejects a coin of a leg if its denom is undefined or its amount negative. Zero amounts are
+// accepted: such a leg moves nothing, but its sender must still hold the denom.
+fn check_leg_coin(
+    coin: &Coin,
+    definitions: &BTreeMap<String, DenomDefinition>,
+) -> Result<(), CalculationError> {
+    if !definitions.contains_key(&coin.denom) {
+        return Err(CalculationError::UnknownDenom {
+            denom: coin.denom.clone(),
+        });
+    }
+    if coin.amount < 0 {
+        return Err(CalculationError::NegativeAmount {
+            denom: coin.denom.clone(),
+            amount: coin.amount,
+        });
+    }
+    Ok(())
+}
+
+// Output legs with one leg per (recipient, denom) pair, in order of first appearance. No fees
+// are charged on the receiving side, so an output listed twice is a single credit of the sum,
+// and is matched against the inputs and reported as such.
+#[derive(Debug, Default)]
+struct MergedOutputs {
+    index: BTreeMap<(String, String), usize>,
+    legs: Vec<Leg>,
+}
+
+impl MergedOutputs {
+    fn push(&mut self, leg: Leg) -> Result<(), CalculationError> {
+        let key = (leg.address.clone(), leg.denom.clone());
+        match self.index.get(&key) {
+            Some(&index) => {
+                let merged = &mut self.legs[index].amount;
+                *merged = merged
+                    .checked_add(leg.amount)
+                    .ok_or(CalculationError::AmountOverflow { denom: leg.denom })?;
+            }
+            None => {
+                self.index.insert(key, self.legs.len());
+                self.legs.push(leg);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sums of a single denom's legs, in total and without the legs of the accounts exempt from its
+// fees.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct DenomAggregate {
+    pub(crate) total_input: i128,
+    pub(crate) total_output: i128,
+    pub(crate) non_issuer_input: i128,
+    pub(crate) non_issuer_output: i128,
+}
+
+impl DenomAggregate {
+    // The part of the transfer burn and commission are charged on:
+    // min(non_issuer_input_sum, non_issuer_output_sum).
+    pub(crate) fn fee_base(&self) -> i128 {
+        self.non_issuer_input.min(self.non_issuer_output)
+    }
+
+    // Adds an input leg of `denom`, the aggregated denom. The sums are left unchanged if either
+    // of them overflows.
+    pub(crate) fn add_input(
+        &mut self,
+        denom: &str,
+        amount: i128,
+        is_exempt: bool,
+    ) -> Result<(), CalculationError> {
+        (self.total_input, self.non_issuer_input) = add_leg(
+            denom,
+            amount,
+            is_exempt,
+            self.total_input,
+            self.non_issuer_input,
+        )?;
+        Ok(())
+    }
+
+    // Same as `add_input`, for an output leg.
+    pub(crate) fn add_output(
+        &mut self,
+        denom: &str,
+        amount: i128,
+        is_exempt: bool,
+    ) -> Result<(), CalculationError> {
+        (self.total_output, self.non_issuer_output) = add_leg(
+            denom,
+            amount,
+            is_exempt,
+            self.total_output,
+            self.non_issuer_output,
+        )?;
+        Ok(())
+    }
+}
+
+// The `total` and `non_issuer` sums of a side of a denom's legs once a leg of `amount` is added.
+fn add_leg(
+    denom: &str,
+    amount: i128,
+    is_exempt: bool,
+    total: i128,
+    non_issuer: i128,
+) -> Result<(i128, i128), CalculationError> {
+    let total = total.checked_add(amount).ok_or_else(|| overflow(denom))?;
+    let non_issuer = if is_exempt {
+        non_issuer
+    } else {
+        non_issuer
+            .checked_add(amount)
+            .ok_or_else(|| overflow(denom))?
+    };
+    Ok((total, non_issuer))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct DenomAggregates {
+    pub(crate) denoms: BTreeMap<String, DenomAggregate>,
+}
+
+impl DenomAggregates {
+    fn check_matched(&self) -> Result<(), CalculationError> {
+        for (denom, aggregate) in &self.denoms {
+            if aggregate.total_input != aggregate.total_output {
+                return Err(CalculationError::InputOutputMismatch {
+                    denom: denom.clone(),
+                    input: aggregate.total_input,
+                    output: aggregate.total_output,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sums the legs of `tx` per denom, rejecting the transaction if inputs and outputs of any denom
+// do not match. This is the straightforward version of `aggregate_flat`, which the calculation
+// uses and is checked against it.
+#[cfg(test)]
+pub(crate) fn aggregate(
+    tx: &NormalizedTx,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    options: &CalculationOptions,
+) -> Result<DenomAggregates, CalculationError> {
+    let mut aggregates = DenomAggregates::default();
+
+    for leg in &tx.inputs {
+        let is_exempt = is_exempt(&definitions[&leg.denom], &leg.address, options);
+        let aggregate = aggregates.denoms.entry(leg.denom.clone()).or_default();
+        aggregate.add_input(&leg.denom, leg.amount, is_exempt)?;
+    }
+
+    for leg in &tx.outputs {
+        let is_exempt = is_exempt(&definitions[&leg.denom], &leg.address, options);
+        let aggregate = aggregates.denoms.entry(leg.denom.clone()).or_default();
+        aggregate.add_output(&leg.denom, leg.amount, is_exempt)?;
+    }
+
+    aggregates.check_matched()?;
+    Ok(aggregates)
+}
+
+// The most denoms a per-denom structure of a calculation held on this thread, for tests to check
+// that transactions over `CalculationOptions::max_distinct_denoms` are rejected before any is
+// built.
+#[cfg(test)]
+thread_local! {
+    pub(crate) static PEAK_DENOMS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// Records the number of denoms of a per-denom structure in `PEAK_DENOMS`.
+fn record_denoms(_count: usize) {
+    #[cfg(test)]
+    PEAK_DENOMS.with(|peak| peak.set(peak.get().max(_count)));
+}
+
+// Hands out dense ids to strings, in order of first appearance.
+#[derive(Debug, Clone, Default)]
+struct Interner {
+    ids: BTreeMap<String, u32>,
+    names: Vec<String>,
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> u32 {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.names.len() as u32;
+        self.ids.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        id
+    }
+
+    fn id(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).copied()
+    }
+
+    fn name(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+}
+
+// Legs as parallel arrays, the i-th leg being (address_ids[i], denom_ids[i], amounts[i]).
+#[derive(Debug, Clone, Default)]
+struct FlatLegs {
+    address_ids: Vec<u32>,
+    denom_ids: Vec<u32>,
+    amounts: Vec<i128>,
+}
+
+impl FlatLegs {
+    fn push(&mut self, address_id: u32, denom_id: u32, amount: i128) {
+        self.address_ids.push(address_id);
+        self.denom_ids.push(denom_id);
+        self.amounts.push(amount);
+    }
+
+    fn len(&self) -> usize {
+        self.amounts.len()
+    }
+}
+
+// A `NormalizedTx` with its addresses and denoms interned, so that the per-leg loops of the
+// calculation run over compact arrays and index per-denom and per-address tables instead of
+// looking strings up in maps. This matters for transactions with hundreds of thousands of legs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FlatTx {
+    addresses: Interner,
+    denoms: Interner,
+    inputs: FlatLegs,
+    outputs: FlatLegs,
+}
+
+impl FlatTx {
+    pub(crate) fn new(tx: &NormalizedTx) -> Self {
+        let mut flat_tx = FlatTx::default();
+        for leg in &tx.inputs {
+            let address_id = flat_tx.addresses.intern(&leg.address);
+            let denom_id = flat_tx.denoms.intern(&leg.denom);
+            flat_tx.inputs.push(address_id, denom_id, leg.amount);
+        }
+        for leg in &tx.outputs {
+            let address_id = flat_tx.addresses.intern(&leg.address);
+            let denom_id = flat_tx.denoms.intern(&leg.denom);
+            flat_tx.outputs.push(address_id, denom_id, leg.amount);
+        }
+        record_denoms(flat_tx.denoms.names.len());
+        flat_tx
+    }
+
+    fn exemptions(
+        &self,
+        definitions: &BTreeMap<String, DenomDefinition>,
+        options: &CalculationOptions,
+    ) -> FlatExemptions {
+        FlatExemptions {
+            issuer_ids: self
+                .denoms
+                .names
+                .iter()
+                .map(|denom| self.addresses.id(&definitions[denom].issuer))
+                .collect(),
+            tx_exempt: self
+                .addresses
+                .names
+                .iter()
+                .map(|address| options.tx_exempt_accounts.contains(address))
+                .collect(),
+        }
+    }
+}
+
+// `is_exempt` over the ids of a `FlatTx`.
+struct FlatExemptions {
+    // The id of every denom's issuer, if it takes part in the transaction.
+    issuer_ids: Vec<Option<u32>>,
+    // Whether every address is in `CalculationOptions::tx_exempt_accounts`.
+    tx_exempt: Vec<bool>,
+}
+
+impl FlatExemptions {
+    fn is_exempt(&self, address_id: u32, denom_id: usize) -> bool {
+        self.issuer_ids[denom_id] == Some(address_id) || self.tx_exempt[address_id as usize]
+    }
+}
+
+// Sums the legs of `flat_tx` per denom, rejecting the transaction if inputs and outputs of any
+// denom do not match.
+pub(crate) fn aggregate_flat(
+    flat_tx: &FlatTx,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    options: &CalculationOptions,
+) -> Result<DenomAggregates, CalculationError> {
+    let exemptions = flat_tx.exemptions(definitions, options);
+    let names = &flat_tx.denoms.names;
+    let mut sums = vec![DenomAggregate::default(); names.len()];
+
+    let inputs = &flat_tx.inputs;
+    for i in 0..inputs.len() {
+        let denom_id = inputs.denom_ids[i] as usize;
+        let is_exempt = exemptions.is_exempt(inputs.address_ids[i], denom_id);
+        sums[denom_id].add_input(&names[denom_id], inputs.amounts[i], is_exempt)?;
+    }
+
+    let outputs = &flat_tx.outputs;
+    for i in 0..outputs.len() {
+        let denom_id = outputs.denom_ids[i] as usize;
+        let is_exempt = exemptions.is_exempt(outputs.address_ids[i], denom_id);
+        sums[denom_id].add_output(&names[denom_id], outputs.amounts[i], is_exempt)?;
+    }
+
+    let aggregates = DenomAggregates {
+        denoms: names.iter().cloned().zip(sums).collect(),
+    };
+    aggregates.check_matched()?;
+    Ok(aggregates)
+}
+
+// Rejects transactions with a leg from or to one of the reserved addresses of `options`.
+fn check_reserved_addresses(
+    tx: &NormalizedTx,
+    options: &CalculationOptions,
+) -> Result<(), CalculationError> {
+    for leg in tx.inputs.iter().chain(&tx.outputs) {
+        if options.reserved_addresses.contains(&leg.address) {
+            return Err(CalculationError::ReservedAddress {
+                address: leg.address.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn check_recipient_count(
+    tx: &NormalizedTx,
+    options: &CalculationOptions,
+) -> Result<(), CalculationError> {
+    if let Some(max) = options.max_recipients {
+        let recipients: BTreeSet<&str> =
+            tx.outputs.iter().map(|leg| leg.address.as_str()).collect();
+        if recipients.len() > max {
+            return Err(CalculationError::TooManyRecipients {
+                count: recipients.len(),
+                max,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Sets the small-balance rebate of the charges whose sender is entitled to one under the
+// `SmallBalanceRebate` of the denom.
+fn plan_small_balance_rebates<B: BalanceSource + ?Sized>(
+    plan: &mut ChargePlan,
+    original_balances: &B,
+    definitions: &BTreeMap<String, DenomDefinition>,
+) -> Result<(), CalculationError> {
+    if plan
+        .charges
+        .iter()
+        .all(|charge| definitions[&charge.denom].small_balance_rebate.is_none())
+    {
+        return Ok(());
+    }
+    let senders: BTreeSet<&str> = plan
+        .charges
+        .iter()
+        .map(|charge| charge.address.as_str())
+        .collect();
+    let originals = original_balances.original_coins(&senders)?;
+    for charge in &mut plan.charges {
+        let Some(rebate) = &definitions[&charge.denom].small_balance_rebate else {
+            continue;
+        };
+        let held = originals
+            .get(charge.address.as_str())
+            .map_or(0, |coins| coins.amount(&charge.denom));
+        if held < rebate.threshold {
+            let fees = charge.fees()?.max(0);
+            charge.small_balance_rebate = (fees as f64 * rebate.rate).floor() as i128;
+        }
+    }
+    Ok(())
+}
+
+// The balances a calculation starts from: a list of balances as passed to the entry points, or
+// the balances a batch keeps up to date between its transfers.
+pub(crate) trait BalanceSource {
+    // The original coins of every account of `addresses` with an entry, which exists even if it
+    // holds nothing, without copying the balances of the other accounts.
+    fn original_coins<'a>(
+        &'a self,
+        addresses: &BTreeSet<&str>,
+    ) -> Result<BTreeMap<&'a str, CoinSet>, CalculationError>;
+}
+
+// Gathered in a single pass. An account listed several times holds the sum of its entries, which
+// must not overflow.
+impl BalanceSource for [Balance] {
+    fn original_coins<'a>(
+        &'a self,
+        addresses: &BTreeSet<&str>,
+    ) -> Result<BTreeMap<&'a str, CoinSet>, CalculationError> {
+        let mut originals: BTreeMap<&str, CoinSet> = BTreeMap::new();
+        for balance in self {
+            if addresses.contains(balance.address.as_str()) {
+                let coins = originals.entry(balance.address.as_str()).or_default();
+                *coins = coins.checked_add(&CoinSet::from_coins(&balance.coins)?)?;
+            }
+        }
+        Ok(originals)
+    }
+}
+
+impl BalanceSource for BTreeMap<String, CoinSet> {
+    fn original_coins<'a>(
+        &'a self,
+        addresses: &BTreeSet<&str>,
+    ) -> Result<BTreeMap<&'a str, CoinSet>, CalculationError> {
+        Ok(addresses
+            .iter()
+            .filter_map(|address| self.get_key_value(*address))
+            .map(|(address, coins)| (address.as_str(), coins.clone()))
+            .collect())
+    }
+}
+
+// Rejects plans charging a sender more than `options.max_effective_fee_rate` of its principal.
+fn check_effective_fee_rates(
+    plan: &ChargePlan,
+    options: &CalculationOptions,
+) -> Result<(), CalculationError> {
+    if let Some(max_rate) = options.max_effective_fee_rate {
+        for charge in &plan.charges {
+            let fee = charge.fees()?;
+            if fee as f64 > max_rate * charge.amount as f64 {
+                return Err(CalculationError::ConfiscatoryFee {
+                    address: charge.address.clone(),
+                    denom: charge.denom.clone(),
+                    fee,
+                    principal: charge.amount,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether `address` neither pays nor counts towards the fee base of `definition`'s denom: the
+// issuer never does, and neither do the accounts exempted for this calculation.
+fn is_exempt(definition: &DenomDefinition, address: &str, options: &CalculationOptions) -> bool {
+    definition.issuer == address || options.tx_exempt_accounts.contains(address)
+}
+
+// Rejects transactions that are valid on their own but violate a policy enabled in `options`.
+pub(crate) fn check_policies(
+    aggregates: &DenomAggregates,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    options: &CalculationOptions,
+) -> Result<(), CalculationError> {
+    if let Some(rate) = options.secondary_burn_rate {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(CalculationError::InvalidSecondaryBurnRate { rate });
+        }
+    }
+    let min_commission_rate = if options.allow_rebates { -1.0 } else { 0.0 };
+    for denom in aggregates.denoms.keys() {
+        let definition = &definitions[denom];
+        if !(0.0..=1.0).contains(&definition.burn_rate) {
+            return Err(CalculationError::InvalidBurnRate {
+                denom: denom.clone(),
+                rate: definition.burn_rate,
+            });
+        }
+        if !(min_commission_rate..=1.0).contains(&definition.commission_rate) {
+            return Err(CalculationError::InvalidCommissionRate {
+                denom: denom.clone(),
+                rate: definition.commission_rate,
+            });
+        }
+        if let Some(rebate) = &definition.small_balance_rebate {
+            if !(0.0..=1.0).contains(&rebate.rate) || rebate.threshold < 0 {
+                return Err(CalculationError::InvalidSmallBalanceRebate {
+                    denom: denom.clone(),
+                    threshold: rebate.threshold,
+                    rate: rebate.rate,
+                });
+            }
+        }
+    }
+    if options.require_uniform_fee_policy {
+        let mut fee_bearing = aggregates.denoms.keys().map(|denom| {
+            let definition = &definitions[denom];
+            definition.burn_rate > 0.0 || definition.commission_rate > 0.0
+        });
+        if let Some(first) = fee_bearing.next() {
+            if fee_bearing.any(|other| other != first) {
+                return Err(CalculationError::MixedFeePolicy);
+            }
+        }
+    }
+    Ok(())
+}
+
+// What sending a single input leg costs its sender: the sent amount plus the burn and the
+// commission, which is credited to the denom's issuer.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Charge {
+    pub(crate) address: String,
+    pub(crate) denom: String,
+    pub(crate) amount: i128,
+    pub(crate) burn: i128,
+    pub(crate) commission: i128,
+    // The part of `commission` burned instead of credited to the issuer, see
+    // `CalculationOptions::secondary_burn_rate`.
+    pub(crate) secondary_burn: i128,
+    // Refunded to the sender by the issuer, see `SmallBalanceRebate`.
+    pub(crate) small_balance_rebate: i128,
+    pub(crate) issuer: String,
+}
+
+impl Charge {
+    // The burn plus the commission.
+    pub(crate) fn fees(&self) -> Result<i128, CalculationError> {
+        self.burn
+            .checked_add(self.commission)
+            .ok_or_else(|| overflow(&self.denom))
+    }
+
+    // The net debit of the sender; rebates reduce it.
+    pub(crate) fn total(&self) -> Result<i128, CalculationError> {
+        self.amount
+            .checked_add(self.burn)
+            .and_then(|sum| sum.checked_add(self.commission))
+            .and_then(|sum| sum.checked_sub(self.small_balance_rebate))
+            .ok_or_else(|| overflow(&self.denom))
+    }
+
+    // What the issuer pays out of its own balance: a negative commission and the small-balance
+    // rebate.
+    fn issuer_debit(&self) -> Result<i128, CalculationError> {
+        self.small_balance_rebate
+            .checked_sub(self.commission.min(0))
+            .ok_or_else(|| overflow(&self.denom))
+    }
+
+    // What the sender must cover from its original balance: rebates are credited with the other
+    // credits, so they cannot fund the charge they come with.
+    fn payable(&self) -> Result<i128, CalculationError> {
+        self.amount
+            .checked_add(self.burn)
+            .and_then(|sum| sum.checked_add(self.commission.max(0)))
+            .ok_or_else(|| overflow(&self.denom))
+    }
+
+    // What the issuer is credited out of the commission. The secondary burn is a part of a
+    // positive commission, and nothing of a negative one, so this cannot overflow.
+    fn issuer_credit(&self) -> i128 {
+        self.commission - self.secondary_burn
+    }
+}
+
+// The charges of a transaction, one per input leg and in the same order.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChargePlan {
+    pub(crate) charges: Vec<Charge>,
+    // How the burns and commissions of `charges` were rounded.
+    pub(crate) rounding_trace: RoundingTrace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum RoundingMode {
+    // Towards positive infinity, in favour of the burn and the issuer.
+    Ceil,
+}
+
+// A single rounded burn or commission of a sender. The exact share of the fee base the sender
+// is charged on is `numerator / denominator`, i.e. its input times the fee base over the sum of
+// the non-exempt inputs; `result` is that share multiplied by `rate` and rounded according to
+// `mode`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rounding {
+    pub address: String,
+    pub denom: String,
+    // Either `Burn` or `Commission`.
+    pub component: ChangeReason,
+    pub numerator: i128,
+    pub denominator: i128,
+    pub rate: f64,
+    pub mode: RoundingMode,
+    pub result: i128,
+}
+
+// Every rounding decision taken while planning the charges of a transaction, in the order of
+// the input legs, for auditing.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RoundingTrace {
+    pub roundings: Vec<Rounding>,
+}
+
+// The straightforward version of `plan_charges_flat`, which the calculation uses and is checked
+// against it.
+#[cfg(test)]
+pub(crate) fn plan_charges(
+    tx: &NormalizedTx,
+    aggregates: &DenomAggregates,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    options: &CalculationOptions,
+) -> Result<ChargePlan, CalculationError> {
+    let mut rounding_trace = RoundingTrace::default();
+    let charges = tx
+        .inputs
+        .iter()
+        .map(|leg| {
+            let definition = &definitions[&leg.denom];
+            leg_charge(
+                &leg.address,
+                leg.amount,
+                definition,
+                &aggregates.denoms[&leg.denom],
+                is_exempt(definition, &leg.address, options),
+                options.secondary_burn_rate.unwrap_or(0.0),
+                options.trace_rounding.then_some(&mut rounding_trace),
+            )
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(ChargePlan {
+        charges,
+        rounding_trace,
+    })
+}
+
+pub(crate) fn plan_charges_flat(
+    flat_tx: &FlatTx,
+    aggregates: &DenomAggregates,
+    definitions: &BTreeMap<String, DenomDefinition>,
+    options: &CalculationOptions,
+) -> Result<ChargePlan, CalculationError> {
+    let exemptions = flat_tx.exemptions(definitions, options);
+    let denoms: Vec<(&DenomDefinition, &DenomAggregate)> = flat_tx
+        .denoms
+        .names
+        .iter()
+        .map(|denom| (&definitions[denom], &aggregates.denoms[denom]))
+        .collect();
+
+    let mut rounding_trace = RoundingTrace::default();
+    let inputs = &flat_tx.inputs;
+    let charges = (0..inputs.len())
+        .map(|i| {
+            let (address_id, denom_id) = (inputs.address_ids[i], inputs.denom_ids[i] as usize);
+            let (definition, aggregate) = denoms[denom_id];
+            leg_charge(
+                flat_tx.addresses.name(address_id),
+                inputs.amounts[i],
+                definition,
+                aggregate,
+                exemptions.is_exempt(address_id, denom_id),
+                options.secondary_burn_rate.unwrap_or(0.0),
+                options.trace_rounding.then_some(&mut rounding_trace),
+            )
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(ChargePlan {
+        charges,
+        rounding_trace,
+    })
+}
+
+// The charge of `address` sending `amount` of `definition`'s denom, recording how its burn and
+// commission were rounded in `rounding_trace`, if given. Recording fails with `AmountOverflow`
+// if the numerator of the sender's share, its input times the fee base, does not fit.
+fn leg_charge(
+    address: &str,
+    amount: i128,
+    definition: &DenomDefinition,
+    aggregate: &DenomAggregate,
+    is_exempt: bool,
+    secondary_burn_rate: f64,
+    mut rounding_trace: Option<&mut RoundingTrace>,
+) -> Result<Charge, CalculationError> {
+    let mut burn = 0;
+    let mut commission = 0;
+    if !is_exempt && aggregate.non_issuer_input > 0 {
+        // the fee base is split between the senders that pay fees only, in proportion to their
+        // inputs
+        let denominator = aggregate.non_issuer_input;
+        let mut round = |component: ChangeReason, rate: f64| {
+            let total = aggregate.fee_base() as f64 * rate;
+            let result = (total * amount as f64 / denominator as f64).ceil() as i128;
+            if let Some(rounding_trace) = rounding_trace.as_deref_mut() {
+                let numerator = amount
+                    .checked_mul(aggregate.fee_base())
+                    .ok_or_else(|| overflow(&definition.denom))?;
+                rounding_trace.roundings.push(Rounding {
+                    address: address.to_string(),
+                    denom: definition.denom.clone(),
+                    component,
+                    numerator,
+                    denominator,
+                    rate,
+                    mode: RoundingMode::Ceil,
+                    result,
+                });
+            }
+            Ok(result)
+        };
+        burn = round(ChangeReason::Burn, definition.burn_rate)?;
+        commission = round(ChangeReason::Commission, definition.commission_rate)?;
+    }
+    Ok(Charge {
+        address: address.to_string(),
+        denom: definition.denom.clone(),
+        amount,
+        burn,
+        commission,
+        secondary_burn: (commission.max(0) as f64 * secondary_burn_rate).ceil() as i128,
+        small_balance_rebate: 0,
+        issuer: definition.issuer.clone(),
+    })
+}
+
+// The net change of every account touched by a transaction.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct BalanceChanges {
+    // One entry per account whose balance changed, listing only the denoms whose balance
+    // changed. An account appears once however many roles (sender, recipient, issuer, fee payer)
+    // it plays, with all of them netted.
+    pub changes: Vec<Balance>,
+    // The network fees credited to the fee collector.
+    pub fees_collected: Vec<Coin>,
+    // Recipients that had no entry in the original balances, in order of first appearance. An
+    // address listed in the original balances exists even if it holds nothing.
+    pub created_accounts: Vec<String>,
+    // The computational cost of processing the transaction, see `estimate_cost`.
+    pub cost_estimate: u64,
+    // The components of every change when `CalculationOptions::tag_changes` is set, empty
+    // otherwise.
+    pub tagged_changes: Vec<TaggedChange>,
+    // The rounding decisions behind the changes when `CalculationOptions::trace_rounding` is
+    // set, empty otherwise.
+    pub rounding_trace: RoundingTrace,
+    // What the transaction burned and paid in commissions.
+    pub report: TransferReport,
+    // The changes applied to the working balances, in order, when
+    // `CalculationOptions::audit_mutations` is set, empty otherwise.
+    pub mutations: Vec<MutationRecord>,
+    // Totals showing that the changes conserve every denom when
+    // `CalculationOptions::prove_conservation` is set, `None` otherwise.
+    pub conservation_proof: Option<ConservationProof>,
+}
+
+// A single change applied to an account's working balance while applying a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutationRecord {
+    pub address: String,
+    pub denom: String,
+    pub delta: i128,
+    pub reason: ChangeReason,
+}
+
+// The balances a transaction is applied to, optionally recording every change made to them.
+struct WorkingBalances {
+    balances: BTreeMap<String, CoinSet>,
+    mutations: Option<Vec<MutationRecord>>,
+}
+
+impl WorkingBalances {
+    // Changes are applied without checking that the balance covers them, only that it does not
+    // overflow; zero deltas are not applied at all.
+    fn apply(
+        &mut self,
+        address: &str,
+        denom: &str,
+        delta: i128,
+        reason: ChangeReason,
+    ) -> Result<(), CalculationError> {
+        if delta == 0 {
+            return Ok(());
+        }
+        self.balances
+            .entry(address.to_string())
+            .or_default()
+            .credit(denom, delta)?;
+        if let Some(mutations) = &mut self.mutations {
+            mutations.push(MutationRecord {
+                address: address.to_string(),
+                denom: denom.to_string(),
+                delta,
+                reason,
+            });
+        }
+        Ok(())
+    }
+
+    // Same as `apply` with `-amount`.
+    fn debit(
+        &mut self,
+        address: &str,
+        denom: &str,
+        amount: i128,
+        reason: ChangeReason,
+    ) -> Result<(), CalculationError> {
+        self.apply(address, denom, negated(amount, denom)?, reason)
+    }
+
+    fn available(&self, address: &str, denom: &str) -> i128 {
+        self.balances
+            .get(address)
+            .map_or(0, |coins| coins.amount(denom))
+    }
+
+    // Fails unless `address` holds at least `amount` of `denom`.
+    fn require(&self, address: &str, denom: &str, amount: i128) -> Result<(), CalculationError> {
+        let available = self.available(address, denom);
+        if available < amount {
+            return Err(CalculationError::InsufficientBalance {
+                address: address.to_string(),
+                denom: denom.to_string(),
+                required: amount,
+                available,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Applies `plan`, the outputs of `tx` and the fee in `options` to `original_balances`, rejecting
+// the transaction if a sender cannot cover its charges (plus the fee, if it also pays it), and
+// returns the resulting change of every account.
+//
+// All debits are applied before any credit: a sender's charges and fee must be covered by its
+// original balance, whatever it receives in the same transaction (outputs, or commissions as an
+// issuer). This keeps the outcome independent of the order of the legs.
+pub(crate) fn apply_charges<B: BalanceSource + ?Sized>(
+    original_balances: &B,
+    tx: &NormalizedTx,
+    plan: &ChargePlan,
+    options: &CalculationOptions,
+) -> Result<BalanceChanges, CalculationError> {
+    // Only the accounts the transaction touches are indexed, so that running it against a large
+    // snapshot of balances copies no more than those.
+    let mut touched: BTreeSet<&str> = BTreeSet::new();
+    for charge in &plan.charges {
+        touched.insert(&charge.address);
+        touched.insert(&charge.issuer);
+    }
+    touched.extend(tx.outputs.iter().map(|leg| leg.address.as_str()));
+    if let Some(fee) = &options.fee {
+        touched.insert(&fee.payer);
+        touched.insert(&options.fee_collector);
+    }
+    let originals = original_balances.original_coins(&touched)?;
+
+    let mut created_accounts: Vec<String> = Vec::new();
+    let mut seen_recipients: BTreeSet<&str> = BTreeSet::new();
+    for leg in &tx.outputs {
+        if !originals.contains_key(leg.address.as_str()) && seen_recipients.insert(&leg.address) {
+            if options.forbid_account_creation {
+                return Err(CalculationError::UnknownRecipient {
+                    address: leg.address.clone(),
+                });
+            }
+            created_accounts.push(leg.address.clone());
+        }
+    }
+
+    let mut working = WorkingBalances {
+        balances: originals
+            .iter()
+            .map(|(address, coins)| (address.to_string(), coins.clone()))
+            .collect(),
+        mutations: options.audit_mutations.then(Vec::new),
+    };
+
+    for charge in &plan.charges {
+        let payable = charge.payable()?;
+        // a sender must hold the denom it sends, even to send nothing of it
+        let holds_denom = working
+            .balances
+            .get(&charge.address)
+            .is_some_and(|coins| coins.contains(&charge.denom));
+        if !holds_denom {
+            return Err(CalculationError::InsufficientBalance {
+                address: charge.address.clone(),
+                denom: charge.denom.clone(),
+                required: payable,
+                available: 0,
+            });
+        }
+        working.require(&charge.address, &charge.denom, payable)?;
+        let (address, denom) = (&charge.address, &charge.denom);
+        working.debit(address, denom, charge.amount, ChangeReason::Principal)?;
+        working.debit(address, denom, charge.burn, ChangeReason::Burn)?;
+        working.debit(
+            address,
+            denom,
+            charge.commission.max(0),
+            ChangeReason::Commission,
+        )?;
+    }
+
+    let mut fees_collected: Vec<Coin> = Vec::new();
+    if let Some(fee) = &options.fee {
+        for coin in &fee.coins {
+            if coin.amount < 0 {
+                return Err(CalculationError::NegativeAmount {
+                    denom: coin.denom.clone(),
+                    amount: coin.amount,
+                });
+            }
+            // the fee is checked after the transfer charges, so a payer that also sends must
+            // cover both
+            working.require(&fee.payer, &coin.denom, coin.amount)?;
+            working.debit(&fee.payer, &coin.denom, coin.amount, ChangeReason::Fee)?;
+            fees_collected.push(coin.clone());
+        }
+    }
+
+    // rebates are debited from the issuers after their own charges and fees
+    for charge in &plan.charges {
+        let issuer_debit = charge.issuer_debit()?;
+        if issuer_debit <= 0 {
+            continue;
+        }
+        working.require(&charge.issuer, &charge.denom, issuer_debit)?;
+        working.apply(
+            &charge.issuer,
+            &charge.denom,
+            charge.commission.min(0),
+            ChangeReason::Commission,
+        )?;
+        working.debit(
+            &charge.issuer,
+            &charge.denom,
+            charge.small_balance_rebate,
+            ChangeReason::Rebate,
+        )?;
+    }
+
+    for charge in &plan.charges {
+        if charge.commission > 0 {
+            working.apply(
+                &charge.issuer,
+                &charge.denom,
+                charge.issuer_credit(),
+                ChangeReason::Commission,
+            )?;
+        } else {
+            working.apply(
+                &charge.address,
+                &charge.denom,
+                negated(charge.commission, &charge.denom)?,
+                ChangeReason::Commission,
+            )?;
+        }
+        working.apply(
+            &charge.address,
+            &charge.denom,
+            charge.small_balance_rebate,
+            ChangeReason::Rebate,
+        )?;
+    }
+    for coin in &fees_collected {
+        working.apply(
+            &options.fee_collector,
+            &coin.denom,
+            coin.amount,
+            ChangeReason::Fee,
+        )?;
+    }
+    for ((address, denom), amount) in credits(tx)? {
+        working.apply(&address, &denom, amount, ChangeReason::Principal)?;
+    }
+
+    // A denom the account did not hold before (e.g. a commission credited to an issuer in
+    // another of its denoms) starts from zero.
+    let mut deltas: Vec<(String, CoinSet)> = Vec::with_capacity(working.balances.len());
+    for (address, final_coins) in working.balances {
+        let delta = match originals.get(address.as_str()) {
+            Some(original_coins) => final_coins.checked_sub(original_coins)?,
+            None => final_coins,
+        };
+        deltas.push((address, delta));
+    }
+    let changes = nonzero_changes(deltas);
+
+    Ok(BalanceChanges {
+        changes,
+        fees_collected,
+        created_accounts,
+        cost_estimate: 0,
+        tagged_changes: Vec::new(),
+        rounding_trace: RoundingTrace::default(),
+        report: TransferReport::default(),
+        mutations: working.mutations.unwrap_or_default(),
+        conservation_proof: None,
+    })
+}
+
+// Why an account's balance changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum ChangeReason {
+    // The transferred amount itself, sent or received.
+    Principal,
+    Burn,
+    // Commission paid by a sender or credited to the issuer.
+    Commission,
+    // Network fee paid by the payer or credited to the fee collector.
+    Fee,
+    // Small-balance rebate paid by the issuer or credited to a sender.
+    Rebate,
+}
+
+// One component of a balance change. The tagged changes of an account and denom sum up to its
+// net change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaggedChange {
+    pub address: String,
+    pub denom: String,
+    pub amount: i128,
+    pub reason: ChangeReason,
+}
+
+// Breaks the changes of a transaction down into tagged components, leaving out zero amounts.
+pub(crate) fn tag_changes(
+    tx: &NormalizedTx,
+    plan: &ChargePlan,
+    options: &CalculationOptions,
+) -> Result<Vec<TaggedChange>, CalculationError> {
+    let mut tagged = Vec::new();
+    let mut tag = |address: &str, denom: &str, amount: i128, reason: ChangeReason| {
+        if amount != 0 {
+            tagged.push(TaggedChange {
+                address: address.to_string(),
+                denom: denom.to_string(),
+                amount,
+                reason,
+            });
+        }
+    };
+
+    for charge in &plan.charges {
+        let denom = &charge.denom;
+        tag(
+            &charge.address,
+            denom,
+            negated(charge.amount, denom)?,
+            ChangeReason::Principal,
+        );
+        tag(
+            &charge.address,
+            denom,
+            negated(charge.burn, denom)?,
+            ChangeReason::Burn,
+        );
+        tag(
+            &charge.address,
+            denom,
+            negated(charge.commission, denom)?,
+            ChangeReason::Commission,
+        );
+        tag(
+            &charge.issuer,
+            &charge.denom,
+            charge.issuer_credit(),
+            ChangeReason::Commission,
+        );
+        tag(
+            &charge.address,
+            &charge.denom,
+            charge.small_balance_rebate,
+            ChangeReason::Rebate,
+        );
+        tag(
+            &charge.issuer,
+            denom,
+            negated(charge.small_balance_rebate, denom)?,
+            ChangeReason::Rebate,
+        );
+    }
+    if let Some(fee) = &options.fee {
+        for coin in &fee.coins {
+            tag(
+                &fee.payer,
+                &coin.denom,
+                negated(coin.amount, &coin.denom)?,
+                ChangeReason::Fee,
+            );
+            tag(
+                &options.fee_collector,
+                &coin.denom,
+                coin.amount,
+                ChangeReason::Fee,
+            );
+        }
+    }
+    for ((address, denom), amount) in credits(tx)? {
+        tag(&address, &denom, amount, ChangeReason::Principal);
+    }
+    Ok(tagged)
+}
+
+// The totals burned, paid in commissions and received by the recipients of a transaction, per
+// denom. Denoms the transaction sends appear even if nothing was charged on them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferReport {
+    pub denoms: BTreeMap<String, DenomReport>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DenomReport {
+    pub burned: i128,
+    pub commission: i128,
+    pub received: i128,
+}
+
+fn transfer_report(
+    tx: &NormalizedTx,
+    plan: &ChargePlan,
+) -> Result<TransferReport, CalculationError> {
+    let mut report = TransferReport::default();
+    for charge in &plan.charges {
+        let denom = &charge.denom;
+        let denom_report = report.denoms.entry(denom.clone()).or_default();
+        accumulate(&mut denom_report.burned, charge.burn, denom)?;
+        accumulate(&mut denom_report.burned, charge.secondary_burn, denom)?;
+        accumulate(&mut denom_report.commission, charge.issuer_credit(), denom)?;
+    }
+    for ((_, denom), amount) in credits(tx)? {
+        let denom_report = report.denoms.entry(denom.clone()).or_default();
+        accumulate(&mut denom_report.received, amount, &denom)?;
+    }
+    Ok(report)
+}
+
+// Adds `amount` of `denom` to `sum`, or fails with `AmountOverflow` if the sum does not fit.
+fn accumulate(sum: &mut i128, amount: i128, denom: &str) -> Result<(), CalculationError> {
+    *sum = sum.checked_add(amount).ok_or_else(|| overflow(denom))?;
+    Ok(())
+}
+
+// Per-denom totals of a calculation from which anyone can check, with `verify`, that it neither
+// created nor lost coins. The totals are gathered independently: the net changes from the
+// applied balances, the burns from the planned charges and the commissions from the tagged
+// components of the changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConservationProof {
+    pub denoms: BTreeMap<String, DenomConservation>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DenomConservation {
+    // The sum of the positive net changes.
+    pub credited: i128,
+    // The sum of the magnitudes of the negative net changes.
+    pub debited: i128,
+    // Everything taken out of the supply, including the burned part of the commissions.
+    pub burned: i128,
+    // The commissions paid, by the senders or, for rebates, by the issuers.
+    pub commission_debited: i128,
+    // The commissions received, by the issuers or, for rebates, by the senders.
+    pub commission_credited: i128,
+    // The part of the commissions burned instead of credited, see
+    // `CalculationOptions::secondary_burn_rate`.
+    pub commission_burned: i128,
+}
+
+impl ConservationProof {
+    // Checks that, for every denom, what was credited plus what was burned is what was debited,
+    // and that every commission debited was either credited or burned.
+    pub fn verify(&self) -> Result<(), CalculationError> {
+        for (denom, totals) in &self.denoms {
+            // totals that do not fit are no proof either
+            if totals.credited.checked_add(totals.burned) != Some(totals.debited)
+                || totals
+                    .commission_credited
+                    .checked_add(totals.commission_burned)
+                    != Some(totals.commission_debited)
+            {
+                return Err(CalculationError::ConservationViolation {
+                    denom: denom.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn conservation_proof(
+    changes: &[Balance],
+    tagged_changes: &[TaggedChange],
+    plan: &ChargePlan,
+) -> Result<ConservationProof, CalculationError> {
+    let mut proof = ConservationProof::default();
+    for coin in changes.iter().flat_map(|change| &change.coins) {
+        let totals = proof.denoms.entry(coin.denom.clone()).or_default();
+        if coin.amount > 0 {
+            accumulate(&mut totals.credited, coin.amount, &coin.denom)?;
+        } else {
+            accumulate(
+                &mut totals.debited,
+                negated(coin.amount, &coin.denom)?,
+                &coin.denom,
+            )?;
+        }
+    }
+    for charge in &plan.charges {
+        let denom = &charge.denom;
+        let totals = proof.denoms.entry(denom.clone()).or_default();
+        accumulate(&mut totals.burned, charge.burn, denom)?;
+        accumulate(&mut totals.burned, charge.secondary_burn, denom)?;
+        accumulate(&mut totals.commission_burned, charge.secondary_burn, denom)?;
+    }
+    for tagged in tagged_changes
+        .iter()
+        .filter(|tagged| tagged.reason == ChangeReason::Commission)
+    {
+        let totals = proof.denoms.entry(tagged.denom.clone()).or_default();
+        if tagged.amount > 0 {
+            accumulate(
+                &mut totals.commission_credited,
+                tagged.amount,
+                &tagged.denom,
+            )?;
+        } else {
+            accumulate(
+                &mut totals.commission_debited,
+                negated(tagged.amount, &tagged.denom)?,
+                &tagged.denom,
+            )?;
+        }
+    }
+    Ok(proof)
+}
+
+// Renders `report` in the Prometheus text exposition format, one counter family per total:
+//
+//   # TYPE token_burned_total counter
+//   token_burned_total{denom="denom1"} 40
+//   # TYPE token_commission_total counter
+//   token_commission_total{denom="denom1"} 60
+pub fn metrics_text(report: &TransferReport) -> String {
+    let mut text = String::new();
+    let mut family = |name: &str, value: fn(&DenomReport) -> i128| {
+        text.push_str(&format!("# TYPE {} counter\n", name));
+        for (denom, denom_report) in &report.denoms {
+            text.push_str(&format!(
+                "{}{{denom=\"{}\"}} {}\n",
+                name,
+                escape_label_value(denom),
+                value(denom_report)
+            ));
+        }
+    };
+    family("token_burned_total", |denom_report| denom_report.burned);
+    family("token_commission_total", |denom_report| {
+        denom_report.commission
+    });
+    text
+}
+
+// Escapes a label value as the exposition format requires: backslash, double quote and line
+// feed are written as \\, \" and \n.
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// The accounts touched by a transaction, biggest movers first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PositionReport {
+    pub accounts: Vec<AccountPosition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountPosition {
+    pub address: String,
+    // The sum of the absolute changes over all denoms.
+    pub total_abs_change: i128,
+    // The nonzero change of each denom, in denom order.
+    pub deltas: Vec<Coin>,
+}
+
+// Ranks the accounts in `changes` by total absolute change, descending, breaking ties by
+// address. Several entries for the same account are netted; netting them or totalling their
+// absolute changes must not overflow.
+pub fn position_report(changes: &[Balance]) -> Result<PositionReport, CalculationError> {
+    let mut deltas: BTreeMap<&str, CoinSet> = BTreeMap::new();
+    for change in changes {
+        let coins = deltas.entry(change.address.as_str()).or_default();
+        *coins = coins.checked_add(&CoinSet::from_coins(&change.coins)?)?;
+    }
+    let mut accounts: Vec<AccountPosition> = Vec::with_capacity(deltas.len());
+    for (address, coins) in deltas {
+        let deltas: Vec<Coin> = coins
+            .into_coins()
+            .into_iter()
+            .filter(|coin| coin.amount != 0)
+            .collect();
+        let mut total_abs_change: i128 = 0;
+        for coin in &deltas {
+            total_abs_change = coin
+                .amount
+                .checked_abs()
+                .and_then(|amount| total_abs_change.checked_add(amount))
+                .ok_or_else(|| CalculationError::AmountOverflow {
+                    denom: coin.denom.clone(),
+                })?;
+        }
+        accounts.push(AccountPosition {
+            address: address.to_string(),
+            total_abs_change,
+            deltas,
+        });
+    }
+    // The sort is stable, so equal totals stay in address order.
+    accounts.sort_by_key(|account| std::cmp::Reverse(account.total_abs_change));
+    Ok(PositionReport { accounts })
+}
+
+// Gas-like weights of the work done to process a transaction.
+const BASE_COST: u64 = 1_000;
+pub(crate) const COST_PER_LEG: u64 = 100;
+const COST_PER_DENOM: u64 = 50;
+pub(crate) const COST_PER_FEE_COMPUTATION: u64 = 25;
+
+// Estimates the computational cost of processing a transaction as
+//   BASE_COST
+//   + COST_PER_LEG * (input legs + output legs)
+//   + COST_PER_DENOM * distinct denoms
+//   + COST_PER_FEE_COMPUTATION * charges with a burn or a commission
+fn estimate_cost(tx: &NormalizedTx, aggregates: &DenomAggregates, plan: &ChargePlan) -> u64 {
+    let legs = (tx.inputs.len() + tx.outputs.len()) as u64;
+    let denoms = aggregates.denoms.len() as u64;
+    let fee_computations = plan
+        .charges
+        .iter()
+        .filter(|charge| charge.burn > 0 || charge.commission > 0)
+        .count() as u64;
+    BASE_COST
+        + COST_PER_LEG * legs
+        + COST_PER_DENOM * denoms
+        + COST_PER_FEE_COMPUTATION * fee_computations
+}
+
+// The amount actually credited to every (recipient, denom) pair of `multi_send_tx`, which is
+// rejected if malformed (undefined denoms, inputs and outputs that do not match). Balances are
+// not looked at. This is a lookup table for callers, so it is a `HashMap`; the calculation
+// itself only iterates the `BTreeMap` of `credits`.
+pub fn credited_amounts(
+    multi_send_tx: &MultiSend,
+    definitions: &[DenomDefinition],
+) -> Result<HashMap<(String, String), i128>, CalculationError> {
+    let definitions = definition_map(definitions);
+    let tx = normalize(multi_send_tx, &definitions)?;
+    aggregate_flat(
+        &FlatTx::new(&tx),
+        &definitions,
+        &CalculationOptions::default(),
+    )?;
+    Ok(credits(&tx)?.into_iter().collect())
+}
+
+// The credits of `credited_amounts`. There are no receive side fees, so a credit is the sum of
+// the recipient's outputs in that denom; all crediting goes through here so that such fees would
+// only need to be accounted for in one place.
+fn credits(tx: &NormalizedTx) -> Result<BTreeMap<(String, String), i128>, CalculationError> {
+    let mut credited: BTreeMap<(String, String), i128> = BTreeMap::new();
+    for leg in &tx.outputs {
+        accumulate(
+            credited
+                .entry((leg.address.clone(), leg.denom.clone()))
+                .or_insert(0),
+            leg.amount,
+            &leg.denom,
+        )?;
+    }
+    Ok(credited)
+}
+
+// Turns per account deltas into changes, leaving out zero amounts and accounts left without
+// any.
+pub(crate) fn nonzero_changes(deltas: impl IntoIterator<Item = (String, CoinSet)>) -> Vec<Balance> {
+    let mut changes: Vec<Balance> = Vec::new();
+    for (address, delta) in deltas {
+        let coins: Vec<Coin> = delta
+            .into_coins()
+            .into_iter()
+            .filter(|coin| coin.amount != 0)
+            .collect();
+        if !coins.is_empty() {
+            changes.push(Balance { address, coins });
+        }
+    }
+    changes
+}
+
+// Sums `plan` and the outputs of `tx` into per account changes without checking any balances.
+// Accounts whose net change is zero are left out.
+pub(crate) fn net_charges(
+    tx: &NormalizedTx,
+    plan: &ChargePlan,
+) -> Result<BalanceChanges, CalculationError> {
+    Ok(BalanceChanges {
+        changes: nonzero_changes(net_deltas(tx, plan)?),
+        fees_collected: Vec::new(),
+        created_accounts: Vec::new(),
+        cost_estimate: 0,
+        tagged_changes: Vec::new(),
+        rounding_trace: RoundingTrace::default(),
+        report: TransferReport::default(),
+        mutations: Vec::new(),
+        conservation_proof: None,
+    })
+}
+
+fn net_deltas(
+    tx: &NormalizedTx,
+    plan: &ChargePlan,
+) -> Result<BTreeMap<String, CoinSet>, CalculationError> {
+    let mut deltas: BTreeMap<String, CoinSet> = BTreeMap::new();
+    for charge in &plan.charges {
+        deltas
+            .entry(charge.address.clone())
+            .or_default()
+            .debit(&charge.denom, charge.total()?)?;
+        let issuer_delta = charge
+            .issuer_credit()
+            .checked_sub(charge.small_balance_rebate)
+            .ok_or_else(|| overflow(&charge.denom))?;
+        if issuer_delta != 0 {
+            deltas
+                .entry(charge.issuer.clone())
+                .or_default()
+                .credit(&charge.denom, issuer_delta)?;
+        }
+    }
+
+    for ((address, denom), amount) in credits(tx)? {
+        deltas.entry(address).or_default().credit(&denom, amount)?;
+    }
+    Ok(deltas)
+}
+
+// Receives balance changes one coin at a time.
+pub trait ChangeSink {
+    fn change(&mut self, address: &str, denom: &str, delta: i128) -> Result<(), CalculationError>;
+}
+
+// Same as `calculate_balance_changes`, but hands the changes to `out` sorted by address and then
+// denom instead of returning them. The transaction is fully validated before the first change
+// is emitted, so a rejected transaction emits nothing.
+//
+// Memory is O(touched accounts), not O(1): an account's change is only final once every leg has
+// been seen (a sender may also be an issuer or a recipient further down the transaction), so the
+// delta of every touched account is held until the emission starts. What is saved is a copy of
+// every original balance and the resulting `Vec<Balance>`.
+pub fn calculate_balance_changes_streaming(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    out: &mut impl ChangeSink,
+) -> Result<(), CalculationError> {
+    let (tx, plan) = covered_plan(original_balances, definitions, multi_send_tx)?;
+    for (address, delta) in net_deltas(&tx, &plan)? {
+        for coin in delta.into_coins() {
+            if coin.amount != 0 {
+                out.change(&address, &coin.denom, coin.amount)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Same as `calculate_balance_changes_streaming`, applying `options`. The changes are computed in
+// full by `calculate_with_options_ref` before the first is emitted, so this holds the resulting
+// `BalanceChanges` too, and they are emitted in the order of `BalanceChanges::changes`.
+pub fn calculate_streaming_with_options(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    options: &CalculationOptions,
+    out: &mut impl ChangeSink,
+) -> Result<(), CalculationError> {
+    let balance_changes =
+        calculate_with_options_ref(original_balances, definitions, multi_send_tx, options)?;
+    for change in &balance_changes.changes {
+        for coin in &change.coins {
+            out.change(&change.address, &coin.denom, coin.amount)?;
+        }
+    }
+    Ok(())
+}
+
+// The commission credited to every issuer, per (issuer, denom), by `multi_send_tx`, which is
+// validated as `calculate_balance_changes` would, without computing the change of every account.
+pub fn issuer_commission(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+) -> Result<BTreeMap<(String, String), i128>, CalculationError> {
+    let (_, plan) = covered_plan(original_balances, definitions, multi_send_tx)?;
+    let mut commissions: BTreeMap<(String, String), i128> = BTreeMap::new();
+    for charge in &plan.charges {
+        if charge.issuer_credit() != 0 {
+            accumulate(
+                commissions
+                    .entry((charge.issuer.clone(), charge.denom.clone()))
+                    .or_default(),
+                charge.issuer_credit(),
+                &charge.denom,
+            )?;
+        }
+    }
+    Ok(commissions)
+}
+
+// Validates `multi_send_tx` and plans its charges with the default options, checking that every
+// sender covers its charges, and every issuer its rebates, from its original balance as
+// `apply_charges` does. Only the original balances of those accounts are looked at.
+fn covered_plan(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+) -> Result<(NormalizedTx, ChargePlan), CalculationError> {
+    let definitions = definition_map(definitions);
+    let tx = normalize(multi_send_tx, &definitions)?;
+    let options = CalculationOptions::default();
+    let flat_tx = FlatTx::new(&tx);
+    let aggregates = aggregate_flat(&flat_tx, &definitions, &options)?;
+    check_policies(&aggregates, &definitions, &options)?;
+    let mut plan = plan_charges_flat(&flat_tx, &aggregates, &definitions, &options)?;
+    plan_small_balance_rebates(&mut plan, original_balances, &definitions)?;
+
+    let mut debits: BTreeMap<&str, CoinSet> = BTreeMap::new();
+    for charge in &plan.charges {
+        debits
+            .entry(charge.address.as_str())
+            .or_default()
+            .credit(&charge.denom, charge.payable()?)?;
+        let issuer_debit = charge.issuer_debit()?;
+        if issuer_debit > 0 {
+            debits
+                .entry(charge.issuer.as_str())
+                .or_default()
+                .credit(&charge.denom, issuer_debit)?;
+        }
+    }
+    let available = original_balances.original_coins(&debits.keys().copied().collect())?;
+    for (address, debit) in &debits {
+        let coins = available.get(address);
+        for (denom, amount) in &debit.amounts {
+            let held = coins.filter(|coins| coins.contains(denom));
+            let available = held.map_or(0, |coins| coins.amount(denom));
+            if held.is_none() || available < *amount {
+                return Err(CalculationError::InsufficientBalance {
+                    address: address.to_string(),
+                    denom: denom.clone(),
+                    required: *amount,
+                    available,
+                });
+            }
+        }
+    }
+    Ok((tx, plan))
+}
+// A source of denom definitions that has to be awaited, e.g. a node queried over the network.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncDefinitionProvider: Sync {
+    // The definition of `denom`, or `None` if it is not defined.
+    async fn definition(&self, denom: &str) -> Result<Option<DenomDefinition>, String>;
+}
+
+// Same as `calculate_balance_changes`, but fetches the definitions of the denoms in
+// `multi_send_tx` from `provider`, concurrently, before calculating.
+#[cfg(feature = "async")]
+pub async fn calculate_balance_changes_async(
+    original_balances: Vec<Balance>,
+    provider: impl AsyncDefinitionProvider,
+    multi_send_tx: MultiSend,
+) -> Result<Vec<Balance>, CalculationError> {
+    let denoms: BTreeSet<&str> = multi_send_tx
+        .inputs
+        .iter()
+        .chain(&multi_send_tx.outputs)
+        .flat_map(|balance| &balance.coins)
+        .map(|coin| coin.denom.as_str())
+        .collect();
+    let definitions =
+        futures::future::try_join_all(denoms.into_iter().map(|denom| provider.definition(denom)))
+            .await
+            .map_err(|message| CalculationError::Provider { message })?;
+    calculate_balance_changes(
+        original_balances,
+        definitions.into_iter().flatten().collect(),
+        multi_send_tx,
+    )
+}
@@ -0,0 +1,486 @@
+// Serialized forms of balance changes and of the state and inputs of a calculation.
+
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
+
+use crate::calc::{calculate_balance_changes_ref, BalanceChanges, ChangeSink, RejectionLog};
+#[cfg(feature = "json")]
+use crate::calc::{calculate_with_options, CalculationOptions, DenomReport};
+use crate::error::CalculationError;
+use crate::types::{Balance, Coin, DenomDefinition, MultiSend};
+
+// Writes every change as a JSON object on its own line:
+//   {"address":"account1","denom":"denom1","delta":-120}
+pub struct JsonLinesSink<W: std::io::Write> {
+    pub(crate) writer: W,
+}
+
+impl<W: std::io::Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink { writer }
+    }
+}
+
+impl<W: std::io::Write> ChangeSink for JsonLinesSink<W> {
+    fn change(&mut self, address: &str, denom: &str, delta: i128) -> Result<(), CalculationError> {
+        writeln!(
+            self.writer,
+            "{{\"address\":{},\"denom\":{},\"delta\":{}}}",
+            json_string(address),
+            json_string(denom),
+            delta
+        )
+        .map_err(|e| CalculationError::Io {
+            message: e.to_string(),
+        })
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Writes the changes as CSV with an `address,denom,delta` header, quoting fields as RFC 4180
+// requires.
+pub struct CsvSink<W: std::io::Write> {
+    pub(crate) writer: W,
+    pub(crate) header_written: bool,
+}
+
+impl<W: std::io::Write> CsvSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvSink {
+            writer,
+            header_written: false,
+        }
+    }
+}
+
+impl<W: std::io::Write> ChangeSink for CsvSink<W> {
+    fn change(&mut self, address: &str, denom: &str, delta: i128) -> Result<(), CalculationError> {
+        if !self.header_written {
+            writeln!(self.writer, "address,denom,delta").map_err(|e| CalculationError::Io {
+                message: e.to_string(),
+            })?;
+            self.header_written = true;
+        }
+        writeln!(
+            self.writer,
+            "{},{},{}",
+            csv_field(address),
+            csv_field(denom),
+            delta
+        )
+        .map_err(|e| CalculationError::Io {
+            message: e.to_string(),
+        })
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl RejectionLog {
+    // Writes every rejection as a JSON object on its own line, with `null` for a missing address
+    // or denom:
+    //   {"index":3,"category":"unknown_denom","address":null,"denom":"denom9",
+    //    "message":"Unknown denom denom9"}
+    pub fn write_jsonl(&self, mut writer: impl std::io::Write) -> Result<(), CalculationError> {
+        let optional =
+            |value: &Option<String>| value.as_deref().map_or("null".to_string(), json_string);
+        for rejection in &self.rejections {
+            writeln!(
+                writer,
+                "{{\"index\":{},\"category\":{},\"address\":{},\"denom\":{},\"message\":{}}}",
+                rejection.index,
+                json_string(rejection.category),
+                optional(&rejection.address),
+                optional(&rejection.denom),
+                json_string(&rejection.error.to_string())
+            )
+            .map_err(|e| CalculationError::Io {
+                message: e.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// Encodes balance changes canonically for storage proofs: one record per coin, sorted by address
+// and then denom, each made of the address and the denom (both prefixed with their length as a
+// big-endian u32) followed by the amount as a 16-byte big-endian signed integer.
+pub fn encode_changes(changes: &[Balance]) -> Vec<u8> {
+    let mut records: Vec<(&str, &str, i128)> = changes
+        .iter()
+        .flat_map(|balance| {
+            balance
+                .coins
+                .iter()
+                .map(|coin| (balance.address.as_str(), coin.denom.as_str(), coin.amount))
+        })
+        .collect();
+    records.sort();
+
+    let mut bytes = Vec::new();
+    for (address, denom, amount) in records {
+        for field in [address, denom] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes.extend_from_slice(&amount.to_be_bytes());
+    }
+    bytes
+}
+
+// Decodes the output of `encode_changes`, grouping consecutive records of the same address into
+// one balance.
+pub fn decode_changes(mut bytes: &[u8]) -> Result<Vec<Balance>, CalculationError> {
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], CalculationError> {
+        if bytes.len() < len {
+            return Err(CalculationError::TruncatedEncoding);
+        }
+        let (head, tail) = bytes.split_at(len);
+        *bytes = tail;
+        Ok(head)
+    }
+    fn take_str(bytes: &mut &[u8]) -> Result<String, CalculationError> {
+        let len = u32::from_be_bytes(take(bytes, 4)?.try_into().unwrap()) as usize;
+        String::from_utf8(take(bytes, len)?.to_vec())
+            .map_err(|_| CalculationError::InvalidUtf8Encoding)
+    }
+
+    let mut changes: Vec<Balance> = Vec::new();
+    while !bytes.is_empty() {
+        let address = take_str(&mut bytes)?;
+        let denom = take_str(&mut bytes)?;
+        let amount = i128::from_be_bytes(take(&mut bytes, 16)?.try_into().unwrap());
+        match changes.last_mut() {
+            Some(last) if last.address == address => last.coins.push(Coin { denom, amount }),
+            _ => changes.push(Balance {
+                address,
+                coins: vec![Coin { denom, amount }],
+            }),
+        }
+    }
+    Ok(changes)
+}
+
+// A 64-bit digest of balance changes: FNV-1a over `encode_changes`, so it does not depend on
+// the order of the changes and is the same on every platform and Rust version.
+pub fn fingerprint(changes: &[Balance]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    encode_changes(changes)
+        .iter()
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(PRIME)
+        })
+}
+
+// Same as `calculate_balance_changes`, but fails unless the fingerprint of the changes is
+// `expected`, e.g. the fingerprint another node computed for the same transaction.
+pub fn calculate_and_verify_fingerprint(
+    original_balances: &[Balance],
+    definitions: &[DenomDefinition],
+    multi_send_tx: &MultiSend,
+    expected: u64,
+) -> Result<Vec<Balance>, CalculationError> {
+    let changes = calculate_balance_changes_ref(original_balances, definitions, multi_send_tx)?;
+    let actual = fingerprint(&changes);
+    if actual != expected {
+        return Err(CalculationError::FingerprintMismatch { expected, actual });
+    }
+    Ok(changes)
+}
+
+// The outcome of a calculation in the shape of an ABCI `ResponseDeliverTx`, for processing loops
+// built around one. `code` is 0 and `codespace` empty for an accepted transaction.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxResult {
+    pub code: u32,
+    pub codespace: String,
+    pub log: String,
+    pub events: Vec<TxEvent>,
+    pub gas_used: u64,
+}
+
+// An ABCI event: a type and its attributes, as key-value pairs in order.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxEvent {
+    pub kind: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl TxEvent {
+    fn new(kind: &str, attributes: &[(&str, String)]) -> Self {
+        TxEvent {
+            kind: kind.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+// Wraps `result` in a `TxResult`. An accepted transaction emits the bank module's events, in the
+// order of its changes: `coin_spent` for every coin an account loses and `coin_received` for
+// every coin it gains, followed by a `burn` per denom burned; its log summarizes them. A rejected
+// one carries the ABCI code of its error, the error's message as its log, and no events.
+// `gas_used` is `gas` if given, or else the cost estimate of an accepted transaction and 0 for a
+// rejected one.
+pub fn to_tx_result(
+    result: &Result<BalanceChanges, CalculationError>,
+    gas: Option<u64>,
+) -> TxResult {
+    match result {
+        Ok(balance_changes) => {
+            let mut events = Vec::new();
+            for change in &balance_changes.changes {
+                for coin in &change.coins {
+                    let (kind, role) = if coin.amount < 0 {
+                        ("coin_spent", "spender")
+                    } else {
+                        ("coin_received", "receiver")
+                    };
+                    events.push(TxEvent::new(
+                        kind,
+                        &[
+                            (role, change.address.clone()),
+                            (
+                                "amount",
+                                format!("{}{}", coin.amount.unsigned_abs(), coin.denom),
+                            ),
+                        ],
+                    ));
+                }
+            }
+            let burned: Vec<String> = balance_changes
+                .report
+                .denoms
+                .iter()
+                .filter(|(_, denom_report)| denom_report.burned != 0)
+                .map(|(denom, denom_report)| format!("{}{}", denom_report.burned, denom))
+                .collect();
+            for amount in &burned {
+                events.push(TxEvent::new("burn", &[("amount", amount.clone())]));
+            }
+            let log = format!(
+                "{} accounts changed, burned [{}]",
+                balance_changes.changes.len(),
+                burned.join(",")
+            );
+            TxResult {
+                code: 0,
+                codespace: String::new(),
+                log,
+                events,
+                gas_used: gas.unwrap_or(balance_changes.cost_estimate),
+            }
+        }
+        Err(error) => TxResult {
+            code: error.abci_code(),
+            codespace: error.codespace().to_string(),
+            log: error.to_string(),
+            events: Vec::new(),
+            gas_used: gas.unwrap_or(0),
+        },
+    }
+}
+
+// Everything a calculation needs besides the transaction, persisted between runs.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct State {
+    pub balances: Vec<Balance>,
+    pub definitions: Vec<DenomDefinition>,
+}
+
+// Writes `state` as a bincode blob. Amounts and rates are stored bit for bit, so loading it back
+// yields exactly the same values.
+#[cfg(feature = "bincode")]
+pub fn save_state(state: &State, writer: impl std::io::Write) -> Result<(), CalculationError> {
+    bincode::serialize_into(writer, state).map_err(|e| CalculationError::InvalidState {
+        message: e.to_string(),
+    })
+}
+
+#[cfg(feature = "bincode")]
+pub fn load_state(reader: impl std::io::Read) -> Result<State, CalculationError> {
+    bincode::deserialize_from(reader).map_err(|e| CalculationError::InvalidState {
+        message: e.to_string(),
+    })
+}
+
+// The newest version of the `Scenario` format `calculate_json` reads. Every version up to it is
+// read; a version only ever adds optional fields.
+#[cfg(feature = "json")]
+pub const SCENARIO_VERSION: u32 = 1;
+
+// The input of `calculate_json`.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Scenario {
+    // The version of the format the scenario is written in, `SCENARIO_VERSION` if missing.
+    #[serde(default = "scenario_version")]
+    pub version: u32,
+    pub balances: Vec<Balance>,
+    pub definitions: Vec<DenomDefinition>,
+    pub tx: MultiSend,
+    #[serde(default)]
+    pub options: CalculationOptions,
+}
+
+// The version of a `Scenario`, read on its own first; the other fields are ignored.
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct ScenarioVersion {
+    #[serde(default = "scenario_version")]
+    version: u32,
+}
+
+#[cfg(feature = "json")]
+fn scenario_version() -> u32 {
+    SCENARIO_VERSION
+}
+
+// Runs the calculation described by a JSON `Scenario`,
+//
+//   {"version": 1, "balances": [...], "definitions": [...],
+//    "tx": {"inputs": [...], "outputs": [...]}, "options": {...}}
+//
+// with `version`, `options`, and any of the options' fields, optional. The version is read
+// before the rest of the scenario, so that a scenario newer than `SCENARIO_VERSION` is rejected
+// with `UnsupportedVersion` even if its new fields would not parse. Returns, as JSON, either
+//
+//   {"ok": {"changes": [...], "burnt": {denom: amount}, "commissions": {denom: amount}}}
+//
+// or, for a rejected calculation, the `CalculationError` as it serializes, its `kind` and
+// fields, along with its message and ABCI code, e.g.
+//
+//   {"err": {"kind": "InsufficientBalance", "address": ..., "denom": ..., "required": ...,
+//            "available": ..., "message": ..., "code": 5, "codespace": "sdk"}}
+//
+// or, for a scenario that cannot be read,
+//
+//   {"err": {"kind": "parse", "message": ..., "line": ..., "column": ...}}
+//
+// Amounts are written as JSON integers whatever their size.
+#[cfg(feature = "json")]
+pub fn calculate_json(scenario_json: &str) -> String {
+    let envelope = match read_scenario(scenario_json) {
+        Err(e) => JsonEnvelope::Err(*e),
+        Ok(scenario) => match calculate_with_options(
+            scenario.balances,
+            scenario.definitions,
+            scenario.tx,
+            &scenario.options,
+        ) {
+            Ok(balance_changes) => {
+                let report = &balance_changes.report.denoms;
+                let totals = |value: fn(&DenomReport) -> i128| -> BTreeMap<String, i128> {
+                    report
+                        .iter()
+                        .map(|(denom, denom_report)| (denom.clone(), value(denom_report)))
+                        .collect()
+                };
+                JsonEnvelope::Ok {
+                    burnt: totals(|denom_report| denom_report.burned),
+                    commissions: totals(|denom_report| denom_report.commission),
+                    changes: balance_changes.changes,
+                }
+            }
+            Err(error) => JsonEnvelope::Err(JsonError::rejected(error)),
+        },
+    };
+    // serialized directly rather than through `serde_json::Value`, which cannot hold every i128
+    serde_json::to_string(&envelope).expect("an envelope has string keys only")
+}
+
+// Reads a `Scenario` of a supported version.
+#[cfg(feature = "json")]
+fn read_scenario(scenario_json: &str) -> Result<Scenario, Box<JsonError>> {
+    let parse_error = |e: serde_json::Error| {
+        Box::new(JsonError::Parse {
+            kind: "parse",
+            message: e.to_string(),
+            line: e.line(),
+            column: e.column(),
+        })
+    };
+    let header: ScenarioVersion = serde_json::from_str(scenario_json).map_err(parse_error)?;
+    if header.version > SCENARIO_VERSION {
+        return Err(Box::new(JsonError::rejected(
+            CalculationError::UnsupportedVersion {
+                version: header.version,
+                supported: SCENARIO_VERSION,
+            },
+        )));
+    }
+    serde_json::from_str(scenario_json).map_err(parse_error)
+}
+
+// The output of `calculate_json`.
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonEnvelope {
+    Ok {
+        changes: Vec<Balance>,
+        burnt: BTreeMap<String, i128>,
+        commissions: BTreeMap<String, i128>,
+    },
+    Err(JsonError),
+}
+
+#[cfg(feature = "json")]
+impl JsonError {
+    fn rejected(error: CalculationError) -> Self {
+        JsonError::Rejected {
+            message: error.to_string(),
+            code: error.abci_code(),
+            codespace: error.codespace(),
+            error,
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum JsonError {
+    Parse {
+        kind: &'static str,
+        message: String,
+        line: usize,
+        column: usize,
+    },
+    Rejected {
+        #[serde(flatten)]
+        error: CalculationError,
+        message: String,
+        code: u32,
+        codespace: &'static str,
+    },
+}
@@ -0,0 +1,398 @@
+// The reasons a calculation can be rejected for.
+
+// Why a calculation, or one of the operations around it, was rejected.
+//
+// With the `serde` feature, an error serializes as an object holding the name of its variant in
+// `kind` next to its fields, e.g.
+//
+//   {"kind": "InsufficientBalance", "address": "account1", "denom": "denom1", "required": 120,
+//    "available": 100}
+//
+// Variant and field names are part of that format: renaming one breaks the clients matching on
+// it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind"))]
+#[non_exhaustive]
+pub enum CalculationError {
+    // A coin of the transaction has no definition.
+    UnknownDenom {
+        denom: String,
+    },
+    // The inputs and outputs of a denom do not add up.
+    InputOutputMismatch {
+        denom: String,
+        input: i128,
+        output: i128,
+    },
+    // `address` has to pay `required` of `denom` but holds only `available`.
+    InsufficientBalance {
+        address: String,
+        denom: String,
+        required: i128,
+        available: i128,
+    },
+    NegativeAmount {
+        denom: String,
+        amount: i128,
+    },
+    // Summing the amounts of `denom` overflowed.
+    AmountOverflow {
+        denom: String,
+    },
+    UnknownRecipient {
+        address: String,
+    },
+    ReservedAddress {
+        address: String,
+    },
+    MixedFeePolicy,
+    ConfiscatoryFee {
+        address: String,
+        denom: String,
+        fee: i128,
+        principal: i128,
+    },
+    TooManyDenoms {
+        limit: usize,
+        counted: usize,
+    },
+    TooManyRecipients {
+        count: usize,
+        max: usize,
+    },
+    InvalidBurnRate {
+        denom: String,
+        rate: f64,
+    },
+    InvalidCommissionRate {
+        denom: String,
+        rate: f64,
+    },
+    // `CalculationOptions::secondary_burn_rate` is not a fraction.
+    InvalidSecondaryBurnRate {
+        rate: f64,
+    },
+    // The small-balance rebate of `denom` has a rate that is not a fraction or a negative
+    // threshold.
+    InvalidSmallBalanceRebate {
+        denom: String,
+        threshold: i128,
+        rate: f64,
+    },
+    InvalidTransferAmount {
+        from: String,
+        to: String,
+        denom: String,
+        amount: i128,
+    },
+    // The fees of `denom` cannot be taken out of its outputs.
+    FeesExceedOutputs {
+        denom: String,
+        fees: i128,
+        outputs: i128,
+    },
+    // The totals of a `ConservationProof` do not add up for `denom`.
+    ConservationViolation {
+        denom: String,
+    },
+    FingerprintMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    // An encoding of balance changes ends in the middle of a record.
+    TruncatedEncoding,
+    // An encoding of balance changes holds an address or denom that is not UTF-8.
+    InvalidUtf8Encoding,
+    // Writing the changes to a `ChangeSink` failed.
+    Io {
+        message: String,
+    },
+    #[cfg(feature = "bincode")]
+    InvalidState {
+        message: String,
+    },
+    // An `AsyncDefinitionProvider` failed to fetch a definition.
+    #[cfg(feature = "async")]
+    Provider {
+        message: String,
+    },
+    // A DOT or Sankey export of a calculation that did not record its mutations.
+    MissingMutations,
+    #[cfg(feature = "json")]
+    UnrepresentableAmount {
+        amount: i128,
+    },
+    // A JSON scenario written in a newer format than this version of the engine reads.
+    #[cfg(feature = "json")]
+    UnsupportedVersion {
+        version: u32,
+        supported: u32,
+    },
+}
+
+impl CalculationError {
+    // The ABCI code a Cosmos SDK chain would reject the transaction with, within `codespace`,
+    // e.g. 5 for insufficient funds in the "sdk" codespace. See `abci_error` for the mapping.
+    pub fn abci_code(&self) -> u32 {
+        self.abci_error().1
+    }
+
+    pub fn codespace(&self) -> &'static str {
+        self.abci_error().0
+    }
+
+    // The name of the variant in snake_case, e.g. "insufficient_balance", for grouping
+    // rejections by kind. The match has no wildcard for the same reason as `abci_error`'s.
+    pub fn category(&self) -> &'static str {
+        match self {
+            CalculationError::UnknownDenom { .. } => "unknown_denom",
+            CalculationError::InputOutputMismatch { .. } => "input_output_mismatch",
+            CalculationError::InsufficientBalance { .. } => "insufficient_balance",
+            CalculationError::NegativeAmount { .. } => "negative_amount",
+            CalculationError::AmountOverflow { .. } => "amount_overflow",
+            CalculationError::UnknownRecipient { .. } => "unknown_recipient",
+            CalculationError::ReservedAddress { .. } => "reserved_address",
+            CalculationError::MixedFeePolicy => "mixed_fee_policy",
+            CalculationError::ConfiscatoryFee { .. } => "confiscatory_fee",
+            CalculationError::TooManyDenoms { .. } => "too_many_denoms",
+            CalculationError::TooManyRecipients { .. } => "too_many_recipients",
+            CalculationError::InvalidBurnRate { .. } => "invalid_burn_rate",
+            CalculationError::InvalidCommissionRate { .. } => "invalid_commission_rate",
+            CalculationError::InvalidSecondaryBurnRate { .. } => "invalid_secondary_burn_rate",
+            CalculationError::InvalidSmallBalanceRebate { .. } => "invalid_small_balance_rebate",
+            CalculationError::InvalidTransferAmount { .. } => "invalid_transfer_amount",
+            CalculationError::FeesExceedOutputs { .. } => "fees_exceed_outputs",
+            CalculationError::ConservationViolation { .. } => "conservation_violation",
+            CalculationError::FingerprintMismatch { .. } => "fingerprint_mismatch",
+            CalculationError::TruncatedEncoding => "truncated_encoding",
+            CalculationError::InvalidUtf8Encoding => "invalid_utf8_encoding",
+            CalculationError::Io { .. } => "io",
+            #[cfg(feature = "bincode")]
+            CalculationError::InvalidState { .. } => "invalid_state",
+            #[cfg(feature = "async")]
+            CalculationError::Provider { .. } => "provider",
+            CalculationError::MissingMutations => "missing_mutations",
+            #[cfg(feature = "json")]
+            CalculationError::UnrepresentableAmount { .. } => "unrepresentable_amount",
+            #[cfg(feature = "json")]
+            CalculationError::UnsupportedVersion { .. } => "unsupported_version",
+        }
+    }
+
+    // The account the error is about, if any; for an invalid transfer, its sender.
+    pub fn address(&self) -> Option<&str> {
+        match self {
+            CalculationError::InsufficientBalance { address, .. }
+            | CalculationError::UnknownRecipient { address }
+            | CalculationError::ReservedAddress { address }
+            | CalculationError::ConfiscatoryFee { address, .. } => Some(address),
+            CalculationError::InvalidTransferAmount { from, .. } => Some(from),
+            _ => None,
+        }
+    }
+
+    // The denom the error is about, if any.
+    pub fn denom(&self) -> Option<&str> {
+        match self {
+            CalculationError::UnknownDenom { denom }
+            | CalculationError::InputOutputMismatch { denom, .. }
+            | CalculationError::InsufficientBalance { denom, .. }
+            | CalculationError::NegativeAmount { denom, .. }
+            | CalculationError::AmountOverflow { denom }
+            | CalculationError::ConfiscatoryFee { denom, .. }
+            | CalculationError::InvalidBurnRate { denom, .. }
+            | CalculationError::InvalidCommissionRate { denom, .. }
+            | CalculationError::InvalidSmallBalanceRebate { denom, .. }
+            | CalculationError::InvalidTransferAmount { denom, .. }
+            | CalculationError::FeesExceedOutputs { denom, .. }
+            | CalculationError::ConservationViolation { denom } => Some(denom),
+            _ => None,
+        }
+    }
+
+    // (codespace, code) of every variant, after the errors of the Cosmos SDK (types/errors) and
+    // of its bank module. The match has no wildcard so that a new variant cannot be added
+    // without mapping it.
+    fn abci_error(&self) -> (&'static str, u32) {
+        match self {
+            // ErrInvalidCoins: the coins of the transaction are malformed
+            CalculationError::UnknownDenom { .. }
+            | CalculationError::NegativeAmount { .. }
+            | CalculationError::AmountOverflow { .. }
+            | CalculationError::InvalidTransferAmount { .. } => ("sdk", 10),
+            // bank's ErrInputOutputMismatch
+            CalculationError::InputOutputMismatch { .. } => ("bank", 4),
+            // ErrInsufficientFunds
+            CalculationError::InsufficientBalance { .. } => ("sdk", 5),
+            // ErrUnknownAddress
+            CalculationError::UnknownRecipient { .. } => ("sdk", 9),
+            // ErrUnauthorized, as the bank module rejects sends to blocked addresses
+            CalculationError::ReservedAddress { .. } => ("sdk", 4),
+            // ErrInvalidRequest: a valid transaction refused by a policy or limit, or a request
+            // the engine cannot serve
+            CalculationError::MixedFeePolicy
+            | CalculationError::ConfiscatoryFee { .. }
+            | CalculationError::TooManyDenoms { .. }
+            | CalculationError::TooManyRecipients { .. }
+            | CalculationError::InvalidBurnRate { .. }
+            | CalculationError::InvalidCommissionRate { .. }
+            | CalculationError::InvalidSecondaryBurnRate { .. }
+            | CalculationError::InvalidSmallBalanceRebate { .. }
+            | CalculationError::FeesExceedOutputs { .. } => ("sdk", 18),
+            // ErrLogic: the engine contradicts itself
+            CalculationError::ConservationViolation { .. } => ("sdk", 35),
+            // ErrConflict: another node computed different changes
+            CalculationError::FingerprintMismatch { .. } => ("sdk", 36),
+            // ErrTxDecode
+            CalculationError::TruncatedEncoding | CalculationError::InvalidUtf8Encoding => {
+                ("sdk", 2)
+            }
+            // ErrIO
+            CalculationError::Io { .. } => ("sdk", 39),
+            #[cfg(feature = "bincode")]
+            CalculationError::InvalidState { .. } => ("sdk", 2),
+            // ErrInternal, whose codespace is "undefined"
+            #[cfg(feature = "async")]
+            CalculationError::Provider { .. } => ("undefined", 1),
+            CalculationError::MissingMutations => ("sdk", 18),
+            // ErrJSONMarshal
+            #[cfg(feature = "json")]
+            CalculationError::UnrepresentableAmount { .. } => ("sdk", 16),
+            // ErrTxDecode
+            #[cfg(feature = "json")]
+            CalculationError::UnsupportedVersion { .. } => ("sdk", 2),
+        }
+    }
+}
+
+impl std::fmt::Display for CalculationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CalculationError::UnknownDenom { denom } => {
+                write!(f, "Unknown denom {}", denom)
+            }
+            CalculationError::InputOutputMismatch {
+                denom,
+                input,
+                output,
+            } => write!(
+                f,
+                "Input and output does not match: {} {} in, {} out",
+                input, denom, output
+            ),
+            CalculationError::InsufficientBalance {
+                address,
+                denom,
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough balance: {} needs {} {} but holds {}",
+                address, required, denom, available
+            ),
+            CalculationError::NegativeAmount { denom, amount } => {
+                write!(f, "Negative amount {} {}", amount, denom)
+            }
+            CalculationError::AmountOverflow { denom } => write!(f, "Amount overflow of {}", denom),
+            CalculationError::UnknownRecipient { address } => {
+                write!(f, "Unknown recipient {}", address)
+            }
+            CalculationError::ReservedAddress { address } => {
+                write!(f, "Reserved address {}", address)
+            }
+            CalculationError::MixedFeePolicy => {
+                write!(f, "Transaction mixes fee-bearing and fee-free denoms")
+            }
+            CalculationError::ConfiscatoryFee {
+                address,
+                denom,
+                fee,
+                principal,
+            } => write!(
+                f,
+                "Confiscatory fee: {} would pay {} {} on a principal of {}",
+                address, fee, denom, principal
+            ),
+            CalculationError::TooManyDenoms { limit, counted } => {
+                write!(f, "Too many denoms: limit {}, counted {}", limit, counted)
+            }
+            CalculationError::TooManyRecipients { count, max } => write!(
+                f,
+                "Too many recipients: {} over a maximum of {}",
+                count, max
+            ),
+            CalculationError::InvalidBurnRate { denom, rate } => {
+                write!(f, "Invalid burn rate {} for {}", rate, denom)
+            }
+            CalculationError::InvalidCommissionRate { denom, rate } => {
+                write!(f, "Invalid commission rate {} for {}", rate, denom)
+            }
+            CalculationError::InvalidSecondaryBurnRate { rate } => {
+                write!(f, "Invalid secondary burn rate {}", rate)
+            }
+            CalculationError::InvalidSmallBalanceRebate {
+                denom,
+                threshold,
+                rate,
+            } => write!(
+                f,
+                "Invalid small-balance rebate for {}: rate {}, threshold {}",
+                denom, rate, threshold
+            ),
+            CalculationError::InvalidTransferAmount {
+                from,
+                to,
+                denom,
+                amount,
+            } => write!(
+                f,
+                "Invalid transfer amount {} {} from {} to {}",
+                amount, denom, from, to
+            ),
+            CalculationError::FeesExceedOutputs {
+                denom,
+                fees,
+                outputs,
+            } => write!(
+                f,
+                "Fees exceed the outputs of {}: {} over {}",
+                denom, fees, outputs
+            ),
+            CalculationError::ConservationViolation { denom } => {
+                write!(f, "Coins of {} are not conserved", denom)
+            }
+            CalculationError::FingerprintMismatch { expected, actual } => write!(
+                f,
+                "Fingerprint mismatch: expected {:016x}, actual {:016x}",
+                expected, actual
+            ),
+            CalculationError::TruncatedEncoding => write!(f, "Truncated balance changes encoding"),
+            CalculationError::InvalidUtf8Encoding => {
+                write!(f, "Invalid UTF-8 in balance changes encoding")
+            }
+            CalculationError::Io { message } => write!(f, "I/O error: {}", message),
+            #[cfg(feature = "bincode")]
+            CalculationError::InvalidState { message } => write!(f, "Invalid state: {}", message),
+            #[cfg(feature = "async")]
+            CalculationError::Provider { message } => {
+                write!(f, "Definition provider failed: {}", message)
+            }
+            CalculationError::MissingMutations => {
+                write!(f, "flow export needs the mutations of the calculation")
+            }
+            #[cfg(feature = "json")]
+            CalculationError::UnrepresentableAmount { amount } => {
+                write!(f, "Amount {} does not fit in JSON", amount)
+            }
+            #[cfg(feature = "json")]
+            CalculationError::UnsupportedVersion { version, supported } => write!(
+                f,
+                "Unsupported scenario version {}, the newest supported is {}",
+                version, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CalculationError {}
@@ -0,0 +1,26 @@
+// Calculates the balance changes of Coreum-style MultiSend transactions, charging the burn and
+// commission rates the issuers of the denoms define. See `calculate_balance_changes`.
+//
+// Stability: the public enums, the option structs and `BalanceChanges` are `#[non_exhaustive]`,
+// so options are built with `CalculationOptions::default().with_...()` and enums are matched with
+// a wildcard arm. Changes come sorted by address (or in first-appearance order with
+// `OutputOrder::FirstAppearance`), with the coins of an account in denom order, and the bytes of
+// `encoding::encode_changes` and `encoding::fingerprint` are the same across versions and
+// platforms. Changing any of these is a breaking change; `tests/api_stability.rs` checks them
+// against the snapshot of public items in `tests/public_api.txt`.
+
+pub mod calc;
+pub mod encoding;
+pub mod error;
+pub mod types;
+pub mod viz;
+
+#[cfg(test)]
+mod tests;
+
+pub use calc::{
+    calculate_balance_changes, calculate_balance_changes_ref, calculate_with_options,
+    calculate_with_options_ref, BalanceChanges, CalculationOptions,
+};
+pub use error::CalculationError;
+pub use types::{Balance, Coin, DenomDefinition, MultiSend};
@@ -0,0 +1,499 @@
+// The coins, balances and transactions the calculation works on.
+
+use std::collections::BTreeMap;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
+use crate::error::CalculationError;
+
+// A user can submit a `MultiSend` transaction (similar to bank.MultiSend in cosmos sdk) to transfer multiple
+// coins (denoms) from multiple input addresses to multiple output addresses. A denom is the name or symbol
+// for a coin type, e.g USDT and USDC can be considered different denoms; in cosmos ecosystem they are called
+// denoms, in ethereum world they are called symbols.
+// The sum of input coins and output coins must match for every transaction.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MultiSend {
+    // inputs contain the list of accounts that want to send coins from, and how many coins from each account we want to send.
+    pub inputs: Vec<Balance>,
+    // outputs contains the list of accounts that we want to deposit coins into, and how many coins to deposit into
+    // each account
+    pub outputs: Vec<Balance>,
+}
+
+impl MultiSend {
+    // Merges the coins of every address per denom, sorts addresses and denoms, and drops zero
+    // amounts, so that equivalent transactions share a single representation. Negative amounts
+    // are rejected.
+    pub fn canonicalize(self) -> Result<CanonicalMultiSend, CalculationError> {
+        Ok(CanonicalMultiSend {
+            inputs: canonical_balances(self.inputs)?,
+            outputs: canonical_balances(self.outputs)?,
+        })
+    }
+
+    // Composes a canonical transaction from `(from, to, coin)` transfers, with one input per
+    // sender and one output per recipient. Every amount must be positive.
+    pub fn from_transfers(
+        transfers: &[(String, String, Coin)],
+    ) -> Result<MultiSend, CalculationError> {
+        let mut inputs = Vec::with_capacity(transfers.len());
+        let mut outputs = Vec::with_capacity(transfers.len());
+        for (from, to, coin) in transfers {
+            if coin.amount <= 0 {
+                return Err(CalculationError::InvalidTransferAmount {
+                    from: from.clone(),
+                    to: to.clone(),
+                    denom: coin.denom.clone(),
+                    amount: coin.amount,
+                });
+            }
+            inputs.push(Balance {
+                address: from.clone(),
+                coins: vec![coin.clone()],
+            });
+            outputs.push(Balance {
+                address: to.clone(),
+                coins: vec![coin.clone()],
+            });
+        }
+        Ok(MultiSend { inputs, outputs }.canonicalize()?.into())
+    }
+
+    // Decomposes the transaction into `(from, to, coin)` transfers for display, pairing senders
+    // and recipients of each denom greedily in leg order. Many decompositions produce the same
+    // transaction, so this one is plausible rather than the original intent. Amounts that do
+    // not match between inputs and outputs are left out.
+    pub fn approximate_transfers(&self) -> Vec<(String, String, Coin)> {
+        type Legs<'a> = BTreeMap<&'a str, Vec<(&'a str, i128)>>;
+        fn legs(balances: &[Balance]) -> Legs<'_> {
+            let mut legs: Legs = BTreeMap::new();
+            for balance in balances {
+                for coin in balance.coins.iter().filter(|coin| coin.amount > 0) {
+                    legs.entry(coin.denom.as_str())
+                        .or_default()
+                        .push((balance.address.as_str(), coin.amount));
+                }
+            }
+            legs
+        }
+
+        let mut outputs = legs(&self.outputs);
+        let mut transfers = Vec::new();
+        for (denom, inputs) in legs(&self.inputs) {
+            let Some(outputs) = outputs.get_mut(denom) else {
+                continue;
+            };
+            let mut outputs = outputs.iter_mut();
+            let mut output = outputs.next();
+            for (from, mut remaining) in inputs {
+                while remaining > 0 {
+                    let Some((to, receivable)) = output.as_mut() else {
+                        break;
+                    };
+                    let amount = remaining.min(*receivable);
+                    transfers.push((
+                        from.to_string(),
+                        to.to_string(),
+                        Coin {
+                            denom: denom.to_string(),
+                            amount,
+                        },
+                    ));
+                    remaining -= amount;
+                    *receivable -= amount;
+                    if *receivable == 0 {
+                        output = outputs.next();
+                    }
+                }
+            }
+        }
+        transfers
+    }
+}
+
+// A `MultiSend` with at most one balance per address and one coin per denom, sorted by address
+// and denom, and without zero amounts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalMultiSend {
+    pub inputs: Vec<Balance>,
+    pub outputs: Vec<Balance>,
+}
+
+impl From<CanonicalMultiSend> for MultiSend {
+    fn from(canonical: CanonicalMultiSend) -> Self {
+        MultiSend {
+            inputs: canonical.inputs,
+            outputs: canonical.outputs,
+        }
+    }
+}
+
+fn canonical_balances(balances: Vec<Balance>) -> Result<Vec<Balance>, CalculationError> {
+    let mut merged: BTreeMap<String, BTreeMap<String, i128>> = BTreeMap::new();
+    for balance in balances {
+        for coin in balance.coins {
+            if coin.amount < 0 {
+                return Err(CalculationError::NegativeAmount {
+                    denom: coin.denom,
+                    amount: coin.amount,
+                });
+            }
+            let amount = merged
+                .entry(balance.address.clone())
+                .or_default()
+                .entry(coin.denom.clone())
+                .or_insert(0);
+            *amount = amount
+                .checked_add(coin.amount)
+                .ok_or(CalculationError::AmountOverflow { denom: coin.denom })?;
+        }
+    }
+
+    Ok(merged
+        .into_iter()
+        .map(|(address, coins)| Balance {
+            address,
+            coins: coins
+                .into_iter()
+                .filter(|(_, amount)| *amount != 0)
+                .map(|(denom, amount)| Coin { denom, amount })
+                .collect(),
+        })
+        .filter(|balance| !balance.coins.is_empty())
+        .collect())
+}
+
+// Assembles a `MultiSend` leg by leg.
+#[derive(Debug, Clone, Default)]
+pub struct MultiSendBuilder {
+    pub(crate) inputs: Vec<Balance>,
+    pub(crate) outputs: Vec<Balance>,
+    pub(crate) change_address: Option<String>,
+}
+
+impl MultiSendBuilder {
+    pub fn new() -> Self {
+        MultiSendBuilder::default()
+    }
+
+    pub fn input(mut self, address: &str, coins: Vec<Coin>) -> Self {
+        self.inputs.push(Balance {
+            address: address.to_string(),
+            coins,
+        });
+        self
+    }
+
+    pub fn output(mut self, address: &str, coins: Vec<Coin>) -> Self {
+        self.outputs.push(Balance {
+            address: address.to_string(),
+            coins,
+        });
+        self
+    }
+
+    // Sends whatever the inputs hold beyond the outputs back to `address`, as an extra output
+    // with one coin per denom, so that inputs and outputs match.
+    pub fn with_change_address(mut self, address: &str) -> Self {
+        self.change_address = Some(address.to_string());
+        self
+    }
+
+    // Fails if the change of a denom would be negative, i.e. the outputs exceed the inputs.
+    pub fn build(mut self) -> Result<MultiSend, CalculationError> {
+        if let Some(change_address) = self.change_address {
+            let mut input = CoinSet::default();
+            for balance in &self.inputs {
+                input = input.checked_add(&CoinSet::from_coins(&balance.coins)?)?;
+            }
+            let mut output = CoinSet::default();
+            for balance in &self.outputs {
+                output = output.checked_add(&CoinSet::from_coins(&balance.coins)?)?;
+            }
+            let change = input.checked_sub(&output)?;
+            if let Some(denom) = change
+                .amounts
+                .iter()
+                .find(|(_, amount)| **amount < 0)
+                .map(|(denom, _)| denom)
+            {
+                return Err(CalculationError::InputOutputMismatch {
+                    denom: denom.clone(),
+                    input: input.amount(denom),
+                    output: output.amount(denom),
+                });
+            }
+            let coins: Vec<Coin> = change
+                .into_coins()
+                .into_iter()
+                .filter(|coin| coin.amount != 0)
+                .collect();
+            if !coins.is_empty() {
+                self.outputs.push(Balance {
+                    address: change_address,
+                    coins,
+                });
+            }
+        }
+        Ok(MultiSend {
+            inputs: self.inputs,
+            outputs: self.outputs,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Coin {
+    pub denom: String,
+    pub amount: i128,
+}
+
+impl PartialEq for Coin {
+    fn eq(&self, other: &Self) -> bool {
+        self.denom == other.denom && self.amount == other.amount
+    }
+}
+
+impl Coin {
+    pub fn new(denom: &str, amount: i128) -> Coin {
+        Coin {
+            denom: denom.to_string(),
+            amount,
+        }
+    }
+
+    // Shows the amount in human units next to base units, for a denom whose human unit is
+    // 10^decimals base units, e.g. `1.500000 denom1 (1500000 base)` for 6 decimals.
+    pub fn format_with_decimals(&self, decimals: u8) -> String {
+        let decimals = decimals as usize;
+        // work on the digits so that any number of decimals fits, even beyond what an i128 power
+        // of ten can hold
+        let digits = format!(
+            "{:0>width$}",
+            self.amount.unsigned_abs(),
+            width = decimals + 1
+        );
+        let (units, fraction) = digits.split_at(digits.len() - decimals);
+        let sign = if self.amount < 0 { "-" } else { "" };
+        let human = if decimals == 0 {
+            format!("{}{}", sign, units)
+        } else {
+            format!("{}{}.{}", sign, units, fraction)
+        };
+        format!("{} {} ({} base)", human, self.denom, self.amount)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Balance {
+    pub address: String,
+    pub coins: Vec<Coin>,
+}
+
+impl Balance {
+    pub fn new(address: &str, coins: Vec<Coin>) -> Balance {
+        Balance {
+            address: address.to_string(),
+            coins,
+        }
+    }
+}
+
+// Balances are equal when they have the same address and the same coins in any order. Coins are
+// compared as a multiset: a coin listed twice in one balance must be listed twice in the other.
+impl PartialEq for Balance {
+    fn eq(&self, other: &Self) -> bool {
+        fn sorted_coins(balance: &Balance) -> Vec<(&str, i128)> {
+            let mut coins: Vec<(&str, i128)> = balance
+                .coins
+                .iter()
+                .map(|coin| (coin.denom.as_str(), coin.amount))
+                .collect();
+            coins.sort_unstable();
+            coins
+        }
+        self.address == other.address && sorted_coins(self) == sorted_coins(other)
+    }
+}
+
+// Amounts held (or owed) per denom. The methods report overflow as
+// `CalculationError::AmountOverflow` and are what every sum of caller-supplied amounts goes
+// through. The operators panic on overflow instead, so they are only used where it cannot
+// happen, e.g. to re-add a change the calculation computed to the balance it was computed from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CoinSet {
+    pub(crate) amounts: BTreeMap<String, i128>,
+}
+
+impl CoinSet {
+    pub(crate) fn from_coins(coins: &[Coin]) -> Result<Self, CalculationError> {
+        let mut set = CoinSet::default();
+        for coin in coins {
+            set.credit(&coin.denom, coin.amount)?;
+        }
+        Ok(set)
+    }
+
+    pub(crate) fn amount(&self, denom: &str) -> i128 {
+        self.amounts.get(denom).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn contains(&self, denom: &str) -> bool {
+        self.amounts.contains_key(denom)
+    }
+
+    pub(crate) fn credit(&mut self, denom: &str, amount: i128) -> Result<(), CalculationError> {
+        let current = self.amounts.entry(denom.to_string()).or_insert(0);
+        *current = current.checked_add(amount).ok_or_else(|| overflow(denom))?;
+        Ok(())
+    }
+
+    pub(crate) fn debit(&mut self, denom: &str, amount: i128) -> Result<(), CalculationError> {
+        self.credit(denom, negated(amount, denom)?)
+    }
+
+    pub(crate) fn checked_add(&self, other: &CoinSet) -> Result<CoinSet, CalculationError> {
+        let mut sum = self.clone();
+        for (denom, amount) in &other.amounts {
+            sum.credit(denom, *amount)?;
+        }
+        Ok(sum)
+    }
+
+    pub(crate) fn checked_sub(&self, other: &CoinSet) -> Result<CoinSet, CalculationError> {
+        let mut difference = self.clone();
+        for (denom, amount) in &other.amounts {
+            let current = difference.amounts.entry(denom.clone()).or_insert(0);
+            *current = current
+                .checked_sub(*amount)
+                .ok_or_else(|| overflow(denom))?;
+        }
+        Ok(difference)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_zero(&self) -> bool {
+        self.amounts.values().all(|amount| *amount == 0)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_negative(&self) -> bool {
+        self.amounts.values().any(|amount| *amount < 0)
+    }
+
+    // The coins of the set, zero amounts included.
+    pub(crate) fn into_coins(self) -> Vec<Coin> {
+        self.amounts
+            .into_iter()
+            .map(|(denom, amount)| Coin { denom, amount })
+            .collect()
+    }
+}
+
+// The error of an amount of `denom` that does not fit.
+pub(crate) fn overflow(denom: &str) -> CalculationError {
+    CalculationError::AmountOverflow {
+        denom: denom.to_string(),
+    }
+}
+
+// `-amount`, or the overflow of `denom` for `i128::MIN`, which has no positive counterpart.
+pub(crate) fn negated(amount: i128, denom: &str) -> Result<i128, CalculationError> {
+    amount.checked_neg().ok_or_else(|| overflow(denom))
+}
+
+impl Add<&CoinSet> for CoinSet {
+    type Output = CoinSet;
+
+    fn add(self, other: &CoinSet) -> CoinSet {
+        self.checked_add(other).expect("coin amount overflow")
+    }
+}
+
+impl Sub<&CoinSet> for CoinSet {
+    type Output = CoinSet;
+
+    fn sub(self, other: &CoinSet) -> CoinSet {
+        self.checked_sub(other).expect("coin amount overflow")
+    }
+}
+
+impl AddAssign<&CoinSet> for CoinSet {
+    fn add_assign(&mut self, other: &CoinSet) {
+        *self = self.checked_add(other).expect("coin amount overflow");
+    }
+}
+
+impl SubAssign<&CoinSet> for CoinSet {
+    fn sub_assign(&mut self, other: &CoinSet) {
+        *self = self.checked_sub(other).expect("coin amount overflow");
+    }
+}
+
+impl Neg for CoinSet {
+    type Output = CoinSet;
+
+    fn neg(self) -> CoinSet {
+        CoinSet::default() - &self
+    }
+}
+
+impl Mul<i128> for CoinSet {
+    type Output = CoinSet;
+
+    fn mul(mut self, factor: i128) -> CoinSet {
+        for amount in self.amounts.values_mut() {
+            *amount = amount.checked_mul(factor).expect("coin amount overflow");
+        }
+        self
+    }
+}
+
+// A Denom has a definition (`CoinDefinition`) which contains different attributes related to the denom:
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DenomDefinition {
+    // the unique identifier for the token (e.g `core`, `eth`, `usdt`, etc.)
+    pub denom: String,
+    // The address that created the token
+    pub issuer: String,
+    // burn_rate is a number between 0 and 1. If it is above zero, in every transfer,
+    // some additional tokens will be burnt on top of the transferred value, from the senders address.
+    // The tokens to be burnt are calculated by multiplying the TransferAmount by burn rate, and
+    // rounding it up to an integer value. For example if an account sends 100 token and burn_rate is
+    // 0.2, then 120 (100 + 100 * 0.2) will be deducted from sender account and 100 will be deposited to the recipient
+    // account (i.e 20 tokens will be burnt)
+    pub burn_rate: f64,
+    // commission_rate is exactly same as the burn_rate, but the calculated value will be transferred to the
+    // issuer's account address instead of being burnt.
+    pub commission_rate: f64,
+    // A refund of part of the fees to the senders holding little of the denom, paid by the issuer.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub small_balance_rebate: Option<SmallBalanceRebate>,
+}
+
+impl DenomDefinition {
+    // A definition without a small-balance rebate.
+    pub fn new(denom: &str, issuer: &str, burn_rate: f64, commission_rate: f64) -> DenomDefinition {
+        DenomDefinition {
+            denom: denom.to_string(),
+            issuer: issuer.to_string(),
+            burn_rate,
+            commission_rate,
+            small_balance_rebate: None,
+        }
+    }
+}
+
+// Senders whose original balance of a denom is below `threshold` get `rate` of their burn plus
+// commission on it back from the issuer, rounded down so that the issuer never pays more than
+// the rate implies. The issuer must cover the rebates from its own balance. The rate must lie in
+// [0, 1] and the threshold must not be negative.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SmallBalanceRebate {
+    pub threshold: i128,
+    pub rate: f64,
+}
@@ -0,0 +1,504 @@
+// Exports of the money flow of a transaction for diagrams.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::calc::{
+    aggregate_flat, check_policies, definition_map, normalize, plan_charges_flat, BalanceChanges,
+    CalculationOptions, ChangeReason, ChargePlan, FlatTx, NormalizedTx,
+};
+use crate::error::CalculationError;
+use crate::types::{negated, DenomDefinition, MultiSend};
+
+// The DOT id of the synthetic node burns flow to. It starts with a single `_`, which no id
+// returned by `node_id` does.
+const BURN_NODE: &str = "_burn";
+
+// The DOT id of the node of `address`: the address itself, with another `_` prepended if it
+// starts with one, so that distinct addresses keep distinct ids and none is `BURN_NODE`.
+fn node_id(address: &str) -> String {
+    if address.starts_with('_') {
+        format!("_{}", address)
+    } else {
+        address.to_string()
+    }
+}
+
+// What a `Flow` moves coins for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FlowKind {
+    // The transferred amount, from a sender to a recipient.
+    Principal,
+    // A network fee, from the payer to the fee collector.
+    Fee,
+    // A small-balance rebate, from the issuer to a sender.
+    Rebate,
+    // From a sender to the burned supply.
+    Burn,
+    // From a sender to the issuer, who may burn part of it (see `CommissionBurn`).
+    Commission,
+    // A negative commission, from the issuer to a sender.
+    CommissionRebate,
+    // The secondary burn of the commissions an issuer received, to the burned supply.
+    CommissionBurn,
+}
+
+// Coins moving from an account to another one or, when `to` is `None`, to the burned supply.
+struct Flow<'a> {
+    from: &'a str,
+    to: Option<&'a str>,
+    denom: &'a str,
+    amount: i128,
+    kind: FlowKind,
+}
+
+// The flows behind the mutations of `changes`, which must have been calculated with
+// `CalculationOptions::audit_mutations` set, so that what flows into and out of every account
+// nets to its change exactly, whatever the options of the calculation. Burns and commissions are
+// attributed to the sender paying them, and secondary burns to the issuer. Senders and
+// recipients of the same denom, fee payers and the fee collector, and issuers and the senders
+// they pay rebates to, are paired by `pair_greedily`.
+fn flows<'a>(
+    changes: &'a BalanceChanges,
+    definitions: &'a [DenomDefinition],
+) -> Result<Vec<Flow<'a>>, CalculationError> {
+    if changes.mutations.is_empty() && !changes.changes.is_empty() {
+        return Err(CalculationError::MissingMutations);
+    }
+    let issuers: BTreeMap<&str, &str> = definitions
+        .iter()
+        .map(|definition| (definition.denom.as_str(), definition.issuer.as_str()))
+        .collect();
+
+    type Legs<'a> = (Vec<(&'a str, i128)>, Vec<(&'a str, i128)>);
+    // (kind, denom) -> (debits, credits) to pair
+    let mut unpaired: BTreeMap<(FlowKind, &str), Legs> = BTreeMap::new();
+    // (issuer, denom) -> commissions paid to the issuer minus commissions credited to it
+    let mut commission_burns: BTreeMap<(&str, &str), i128> = BTreeMap::new();
+    let mut flows = Vec::new();
+
+    for mutation in &changes.mutations {
+        let (address, denom, delta) = (
+            mutation.address.as_str(),
+            mutation.denom.as_str(),
+            mutation.delta,
+        );
+        let kind = match mutation.reason {
+            ChangeReason::Principal => FlowKind::Principal,
+            ChangeReason::Fee => FlowKind::Fee,
+            ChangeReason::Rebate => FlowKind::Rebate,
+            ChangeReason::Burn => {
+                flows.push(Flow {
+                    from: address,
+                    to: None,
+                    denom,
+                    amount: negated(delta, denom)?,
+                    kind: FlowKind::Burn,
+                });
+                continue;
+            }
+            ChangeReason::Commission => {
+                let issuer = *issuers
+                    .get(denom)
+                    .ok_or_else(|| CalculationError::UnknownDenom {
+                        denom: denom.to_string(),
+                    })?;
+                match (address == issuer, delta < 0) {
+                    // a commission credited to the issuer
+                    (true, false) => {
+                        *commission_burns.entry((issuer, denom)).or_default() -= delta;
+                        continue;
+                    }
+                    // a commission paid by a sender
+                    (false, true) => {
+                        flows.push(Flow {
+                            from: address,
+                            to: Some(issuer),
+                            denom,
+                            amount: negated(delta, denom)?,
+                            kind: FlowKind::Commission,
+                        });
+                        *commission_burns.entry((issuer, denom)).or_default() -= delta;
+                        continue;
+                    }
+                    // a rebate paid by the issuer or credited to a sender
+                    _ => FlowKind::CommissionRebate,
+                }
+            }
+        };
+        let legs = unpaired.entry((kind, denom)).or_default();
+        if delta < 0 {
+            legs.0.push((address, negated(delta, denom)?));
+        } else {
+            legs.1.push((address, delta));
+        }
+    }
+
+    for ((issuer, denom), burned) in commission_burns {
+        if burned > 0 {
+            flows.push(Flow {
+                from: issuer,
+                to: None,
+                denom,
+                amount: burned,
+                kind: FlowKind::CommissionBurn,
+            });
+        }
+    }
+    for ((kind, denom), (debits, credits)) in unpaired {
+        for (from, to, amount) in pair_greedily(debits, credits) {
+            flows.push(Flow {
+                from,
+                to: Some(to),
+                denom,
+                amount,
+                kind,
+            });
+        }
+    }
+    Ok(flows)
+}
+
+// Pairs the debits and credits of a denom greedily, in order, after netting what an account
+// both pays and receives so that nothing flows from an account to itself.
+fn pair_greedily<'a>(
+    mut debits: Vec<(&'a str, i128)>,
+    mut credits: Vec<(&'a str, i128)>,
+) -> Vec<(&'a str, &'a str, i128)> {
+    let totals = |legs: &[(&'a str, i128)]| {
+        let mut totals: BTreeMap<&str, i128> = BTreeMap::new();
+        for (address, amount) in legs {
+            *totals.entry(address).or_default() += amount;
+        }
+        totals
+    };
+    let received = totals(&credits);
+    let own: BTreeMap<&str, i128> = totals(&debits)
+        .into_iter()
+        .filter_map(|(address, paid)| {
+            received
+                .get(address)
+                .map(|received| (address, paid.min(*received)))
+        })
+        .collect();
+    for legs in [&mut debits, &mut credits] {
+        let mut remaining = own.clone();
+        for (address, amount) in legs.iter_mut() {
+            if let Some(netted) = remaining.get_mut(address) {
+                let share = (*amount).min(*netted);
+                *amount -= share;
+                *netted -= share;
+            }
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let mut credits = credits.into_iter().filter(|(_, amount)| *amount > 0);
+    let mut credit = credits.next();
+    for (from, mut remaining) in debits {
+        while remaining > 0 {
+            let Some((to, receivable)) = credit.as_mut() else {
+                break;
+            };
+            let amount = remaining.min(*receivable);
+            pairs.push((from, *to, amount));
+            remaining -= amount;
+            *receivable -= amount;
+            if *receivable == 0 {
+                credit = credits.next();
+            }
+        }
+    }
+    pairs
+}
+
+// Renders the flows of a calculation as a DOT digraph:
+// - a node per account, labeled with its net change per denom and identified by `node_id`,
+// - a solid edge per transfer, and a gray one per network fee,
+// - a dashed red edge to the burn node for every burn, including the secondary burn of an
+//   issuer's commissions,
+// - a dotted blue edge from every sender to the issuer for the commission it pays,
+// - a dotted green edge from an issuer to every sender it pays a rebate to.
+//
+// The edges come from `flows`, so `changes` must have recorded its mutations and the graph
+// reflects every option it was calculated with. It takes no `MultiSend`: the mutations hold
+// everything that moved, while the stated legs of a transaction differ from it under
+// fee-inclusive amounts or a remainder recipient. Nodes and edges are sorted, so the output only
+// depends on its inputs.
+pub fn to_dot(
+    changes: &BalanceChanges,
+    definitions: &[DenomDefinition],
+) -> Result<String, CalculationError> {
+    // a `to` of `None` is the burn node
+    let mut edges: BTreeMap<(&str, Option<&str>, FlowKind, &str), i128> = BTreeMap::new();
+    for flow in flows(changes, definitions)? {
+        *edges
+            .entry((flow.from, flow.to, flow.kind, flow.denom))
+            .or_default() += flow.amount;
+    }
+
+    let mut nodes: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (from, to, _, _) in edges.keys() {
+        for address in std::iter::once(*from).chain(*to) {
+            nodes.entry(address).or_default();
+        }
+    }
+    for change in &changes.changes {
+        let deltas = nodes.entry(&change.address).or_default();
+        let coins: BTreeSet<(&str, i128)> = change
+            .coins
+            .iter()
+            .map(|coin| (coin.denom.as_str(), coin.amount))
+            .collect();
+        deltas.extend(
+            coins
+                .into_iter()
+                .map(|(denom, amount)| format!("{}: {:+}", denom, amount)),
+        );
+    }
+
+    let mut dot = String::from("digraph money_flow {\n");
+    dot.push_str(&format!(
+        "  {} [label=\"burn\", shape=box, style=dashed];\n",
+        quoted(BURN_NODE)
+    ));
+    for (address, deltas) in &nodes {
+        let mut label = escape(address);
+        for delta in deltas {
+            label.push_str("\\n");
+            label.push_str(&escape(delta));
+        }
+        dot.push_str(&format!(
+            "  {} [label=\"{}\"];\n",
+            quoted(&node_id(address)),
+            label
+        ));
+    }
+    for ((from, to, kind, denom), amount) in &edges {
+        let style = match kind {
+            FlowKind::Principal => "",
+            FlowKind::Fee => ", color=gray",
+            FlowKind::Burn | FlowKind::CommissionBurn => ", style=dashed, color=red",
+            FlowKind::Commission => ", style=dotted, color=blue",
+            FlowKind::Rebate | FlowKind::CommissionRebate => ", style=dotted, color=darkgreen",
+        };
+        dot.push_str(&format!(
+            "  {} -> {} [label=\"{} {}\"{}];\n",
+            quoted(&node_id(from)),
+            quoted(&to.map_or_else(|| BURN_NODE.to_string(), node_id)),
+            escape(denom),
+            amount,
+            style
+        ));
+    }
+    dot.push_str("}\n");
+    Ok(dot)
+}
+
+// The charges of `tx` under `definitions` with the default options, once its rates are validated.
+fn plan(
+    tx: &MultiSend,
+    definitions: &[DenomDefinition],
+) -> Result<(NormalizedTx, ChargePlan), CalculationError> {
+    let definitions = definition_map(definitions);
+    let options = CalculationOptions::default();
+    let normalized_tx = normalize(tx, &definitions)?;
+    let flat_tx = FlatTx::new(&normalized_tx);
+    let aggregates = aggregate_flat(&flat_tx, &definitions, &options)?;
+    check_policies(&aggregates, &definitions, &options)?;
+    let plan = plan_charges_flat(&flat_tx, &aggregates, &definitions, &options)?;
+    Ok((normalized_tx, plan))
+}
+
+fn quoted(id: &str) -> String {
+    format!("\"{}\"", escape(id))
+}
+
+// Escapes a value for a double-quoted DOT string.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// An end of a `FlowEdge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlowNode {
+    Account(String),
+    // Where the inputs of a transaction are pooled before being split between its outputs.
+    Pool,
+    Burn,
+}
+
+// Coins flowing along one edge of the graph of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowEdge {
+    pub from: FlowNode,
+    pub to: FlowNode,
+    pub denom: String,
+    pub amount: i128,
+    // `Principal` for the edges into and out of the pool, `Burn` and `Commission` for the fees.
+    pub reason: ChangeReason,
+}
+
+// The coin flow of `tx` as a graph in which every input flows from its sender into the pool and
+// every output from the pool to its recipient, while burns flow from the senders to the burn
+// node and commissions to the issuers. Burns and commissions are computed with the default
+// options; edges of zero fees are left out. The edges of each input (principal, burn, then
+// commission) come in input order, followed by the outputs in order.
+pub fn flow_edges(
+    tx: &MultiSend,
+    definitions: &[DenomDefinition],
+) -> Result<Vec<FlowEdge>, CalculationError> {
+    let (normalized_tx, plan) = plan(tx, definitions)?;
+    let mut edges = Vec::new();
+    for charge in plan.charges {
+        let sender = FlowNode::Account(charge.address);
+        for (to, amount, reason) in [
+            (FlowNode::Pool, charge.amount, ChangeReason::Principal),
+            (FlowNode::Burn, charge.burn, ChangeReason::Burn),
+            (
+                FlowNode::Account(charge.issuer),
+                charge.commission,
+                ChangeReason::Commission,
+            ),
+        ] {
+            if amount != 0 || reason == ChangeReason::Principal {
+                edges.push(FlowEdge {
+                    from: sender.clone(),
+                    to,
+                    denom: charge.denom.clone(),
+                    amount,
+                    reason,
+                });
+            }
+        }
+    }
+    for leg in normalized_tx.outputs {
+        edges.push(FlowEdge {
+            from: FlowNode::Pool,
+            to: FlowNode::Account(leg.address),
+            denom: leg.denom,
+            amount: leg.amount,
+            reason: ChangeReason::Principal,
+        });
+    }
+    Ok(edges)
+}
+
+// A node of the Sankey export. Nodes are ordered accounts first, then the burned supply, then
+// the commission of every issuer.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum SankeyNode {
+    Account(String),
+    Burned,
+    // The commissions of an issuer's denoms, before they are credited to the issuer or, for a
+    // secondary burn, burned.
+    Commission(String),
+}
+
+// Renders the flows of a calculation for a Sankey diagram as
+//
+//   {"nodes": [{"name": ..., "kind": "account" | "burned" | "commission"}, ...],
+//    "links": [{"source": 0, "target": 1, "denom": ..., "value": ...}, ...]}
+//
+// where `source` and `target` index `nodes`. The links are the `flows` of `changes`, which must
+// have recorded its mutations. Commissions flow from the senders to the commission node of the
+// issuer, and on to the issuer or, for a secondary burn, to the burned supply; rebates of a
+// negative commission flow from the issuer to the senders directly. Like `to_dot`, it takes no
+// transaction: everything it shows is in the mutations.
+#[cfg(feature = "json")]
+pub fn to_sankey_json(
+    changes: &BalanceChanges,
+    definitions: &[DenomDefinition],
+) -> Result<serde_json::Value, CalculationError> {
+    let mut links: BTreeMap<(SankeyNode, SankeyNode, String), i128> = BTreeMap::new();
+    let mut link = |source: SankeyNode, target: SankeyNode, denom: &str, value: i128| {
+        *links
+            .entry((source, target, denom.to_string()))
+            .or_default() += value;
+    };
+    let account = |address: &str| SankeyNode::Account(address.to_string());
+
+    // (issuer, denom) -> commission paid minus commission burned
+    let mut credited_commissions: BTreeMap<(&str, &str), i128> = BTreeMap::new();
+    for flow in flows(changes, definitions)? {
+        match (flow.kind, flow.to) {
+            (FlowKind::Commission, Some(issuer)) => {
+                link(
+                    account(flow.from),
+                    SankeyNode::Commission(issuer.to_string()),
+                    flow.denom,
+                    flow.amount,
+                );
+                *credited_commissions
+                    .entry((issuer, flow.denom))
+                    .or_default() += flow.amount;
+            }
+            (FlowKind::CommissionBurn, _) => {
+                link(
+                    SankeyNode::Commission(flow.from.to_string()),
+                    SankeyNode::Burned,
+                    flow.denom,
+                    flow.amount,
+                );
+                *credited_commissions
+                    .entry((flow.from, flow.denom))
+                    .or_default() -= flow.amount;
+            }
+            (_, Some(to)) => link(account(flow.from), account(to), flow.denom, flow.amount),
+            (_, None) => link(
+                account(flow.from),
+                SankeyNode::Burned,
+                flow.denom,
+                flow.amount,
+            ),
+        }
+    }
+    for ((issuer, denom), credited) in credited_commissions {
+        if credited > 0 {
+            link(
+                SankeyNode::Commission(issuer.to_string()),
+                account(issuer),
+                denom,
+                credited,
+            );
+        }
+    }
+
+    let mut nodes: BTreeSet<SankeyNode> = changes
+        .changes
+        .iter()
+        .map(|change| account(&change.address))
+        .collect();
+    for (source, target, _) in links.keys() {
+        nodes.insert(source.clone());
+        nodes.insert(target.clone());
+    }
+    let ids: BTreeMap<&SankeyNode, usize> = nodes.iter().zip(0..).collect();
+    let nodes: Vec<serde_json::Value> = nodes
+        .iter()
+        .map(|node| match node {
+            SankeyNode::Account(address) => {
+                serde_json::json!({"name": address, "kind": "account"})
+            }
+            SankeyNode::Burned => serde_json::json!({"name": "burned", "kind": "burned"}),
+            SankeyNode::Commission(issuer) => {
+                serde_json::json!({"name": issuer, "kind": "commission"})
+            }
+        })
+        .collect();
+    let links = links
+        .iter()
+        .map(|((source, target, denom), value)| {
+            let value = serde_json::Number::from_i128(*value)
+                .ok_or(CalculationError::UnrepresentableAmount { amount: *value })?;
+            Ok(serde_json::json!({
+                "source": ids[source],
+                "target": ids[target],
+                "denom": denom,
+                "value": value,
+            }))
+        })
+        .collect::<Result<Vec<_>, CalculationError>>()?;
+    Ok(serde_json::json!({"nodes": nodes, "links": links}))
+}
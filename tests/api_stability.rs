@@ -0,0 +1,465 @@
+// Uses every public item of the crate the way a dependent would, from outside the crate, so that
+// removing or renaming one, or changing how it is built, fails to compile here first. Options
+// and result types are `#[non_exhaustive]`: they are built through their builders and matched
+// with a wildcard, as a dependent has to. `test_public_items_match_the_snapshot` also catches
+// changes that still compile, such as a new public item or enum variant.
+
+use std::collections::BTreeSet;
+
+use rust_task::calc::{
+    calculate_balance_changes_from_legs, calculate_balance_changes_streaming, calculate_batch,
+    calculate_batch_logged, calculate_deltas_unchecked, calculate_from_legs_with_options,
+    calculate_streaming_with_options, check_tx, compare_schedules, credited_amounts, deliver_tx,
+    is_noop, issuer_commission, issuer_of, max_fee_over_rate_range, metrics_text, position_report,
+    prepare, prepare_with_options, BatchOp, ChangeReason, ChangeSink, Fee, FeeInclusion,
+    IncrementalCalculator, OutputOrder, RateOverrides, RoundingMode,
+};
+use rust_task::encoding::{
+    calculate_and_verify_fingerprint, decode_changes, encode_changes, fingerprint, to_tx_result,
+    CsvSink, JsonLinesSink,
+};
+use rust_task::types::{MultiSendBuilder, SmallBalanceRebate};
+use rust_task::viz::{self, FlowNode};
+use rust_task::{
+    calculate_balance_changes, calculate_balance_changes_ref, calculate_with_options,
+    calculate_with_options_ref, Balance, BalanceChanges, CalculationError, CalculationOptions,
+    Coin, DenomDefinition, MultiSend,
+};
+
+fn balances() -> Vec<Balance> {
+    vec![
+        Balance::new("account1", vec![Coin::new("denom1", 1000)]),
+        Balance::new("issuer_account_A", vec![Coin::new("denom1", 1000)]),
+    ]
+}
+
+fn definitions() -> Vec<DenomDefinition> {
+    vec![DenomDefinition::new(
+        "denom1",
+        "issuer_account_A",
+        0.08,
+        0.12,
+    )]
+}
+
+fn multi_send_tx() -> MultiSend {
+    MultiSendBuilder::new()
+        .input("account1", vec![Coin::new("denom1", 100)])
+        .output("account_recipient", vec![Coin::new("denom1", 100)])
+        .build()
+        .unwrap()
+}
+
+fn options() -> CalculationOptions {
+    CalculationOptions::default()
+        .with_fee(Fee::new("account1", vec![Coin::new("denom1", 1)]))
+        .with_fee_collector("fee_collector")
+        .with_forbid_account_creation(false)
+        .with_require_uniform_fee_policy(false)
+        .with_tag_changes(true)
+        .with_trace_rounding(true)
+        .with_reserved_address("reserved")
+        .with_tx_exempt_account("exempt")
+        .with_remainder_recipient("account1")
+        .with_audit_mutations(true)
+        .with_max_effective_fee_rate(1.0)
+        .with_secondary_burn_rate(0.0)
+        .with_output_order(OutputOrder::Address)
+        .with_fee_inclusion(FeeInclusion::Exclusive)
+        .with_max_distinct_denoms(10)
+        .with_max_recipients(10)
+        .with_allow_rebates(false)
+        .with_prove_conservation(true)
+}
+
+#[derive(Default)]
+struct CountingSink {
+    changes: usize,
+}
+
+impl ChangeSink for CountingSink {
+    fn change(&mut self, _: &str, _: &str, _: i128) -> Result<(), CalculationError> {
+        self.changes += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_calculation_entry_points() {
+    let changes = calculate_balance_changes(balances(), definitions(), multi_send_tx()).unwrap();
+    assert_eq!(
+        calculate_balance_changes_ref(&balances(), &definitions(), &multi_send_tx()).unwrap(),
+        changes
+    );
+    let balance_changes: BalanceChanges =
+        calculate_with_options(balances(), definitions(), multi_send_tx(), &options()).unwrap();
+    assert_eq!(
+        calculate_with_options_ref(&balances(), &definitions(), &multi_send_tx(), &options())
+            .unwrap(),
+        balance_changes
+    );
+    assert_eq!(
+        calculate_balance_changes_from_legs(
+            &balances(),
+            &definitions(),
+            multi_send_tx().inputs,
+            multi_send_tx().outputs,
+        )
+        .unwrap(),
+        changes
+    );
+    calculate_from_legs_with_options(
+        &balances(),
+        &definitions(),
+        multi_send_tx().inputs,
+        multi_send_tx().outputs,
+        &options(),
+    )
+    .unwrap();
+
+    // the fields of a result are read, never built
+    let BalanceChanges {
+        changes: _,
+        fees_collected,
+        created_accounts,
+        cost_estimate,
+        tagged_changes,
+        rounding_trace,
+        report,
+        mutations,
+        conservation_proof,
+        ..
+    } = balance_changes;
+    assert_eq!(fees_collected, vec![Coin::new("denom1", 1)]);
+    assert_eq!(created_accounts, vec!["account_recipient".to_string()]);
+    assert!(cost_estimate > 0);
+    assert!(tagged_changes
+        .iter()
+        .any(|change| change.reason == ChangeReason::Burn));
+    assert!(rounding_trace
+        .roundings
+        .iter()
+        .all(|rounding| rounding.mode == RoundingMode::Ceil));
+    assert_eq!(report.denoms["denom1"].burned, 8);
+    assert!(!mutations.is_empty());
+    conservation_proof.unwrap().verify().unwrap();
+    assert!(metrics_text(&report).contains("denom1"));
+}
+
+#[test]
+fn test_secondary_entry_points() {
+    let prepared = prepare(&balances(), &definitions(), &multi_send_tx()).unwrap();
+    assert_eq!(prepared.preview().len(), 3);
+    assert_eq!(prepared.commit().len(), 3);
+    prepare_with_options(&balances(), &definitions(), &multi_send_tx(), &options()).unwrap();
+
+    let check_info = check_tx(&multi_send_tx(), &definitions(), &options()).unwrap();
+    assert_eq!(check_info.priority, 20);
+    assert!(check_info.gas_estimate > 0);
+    deliver_tx(&balances(), &definitions(), &multi_send_tx(), &options()).unwrap();
+
+    assert!(!is_noop(&balances(), &definitions(), &multi_send_tx()).unwrap());
+    assert_eq!(
+        calculate_deltas_unchecked(&definitions(), &multi_send_tx())
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(
+        credited_amounts(&multi_send_tx(), &definitions()).unwrap()
+            [&("account_recipient".to_string(), "denom1".to_string())],
+        100
+    );
+    assert_eq!(
+        issuer_commission(&balances(), &definitions(), &multi_send_tx()).unwrap()
+            [&("issuer_account_A".to_string(), "denom1".to_string())],
+        12
+    );
+    assert_eq!(
+        issuer_of(&definitions(), "denom1"),
+        Some("issuer_account_A")
+    );
+    assert_eq!(max_fee_over_rate_range(100, 0.0..=0.1, 0.0..=0.1), Ok(20));
+    assert_eq!(
+        position_report(
+            &calculate_balance_changes(balances(), definitions(), multi_send_tx()).unwrap()
+        )
+        .unwrap()
+        .accounts[0]
+            .address,
+        "account1"
+    );
+
+    let schedules = [RateOverrides::new()
+        .with_burn_rate("denom1", 0.0)
+        .with_commission_rate("denom1", 0.0)];
+    assert_eq!(
+        compare_schedules(&balances(), &definitions(), &multi_send_tx(), &schedules).unwrap()[0]
+            .denoms["denom1"]
+            .burned,
+        0
+    );
+
+    let mut disabled = DenomDefinition::new("denom2", "issuer_account_B", 0.0, 0.0);
+    disabled.small_balance_rebate = Some(SmallBalanceRebate {
+        threshold: 0,
+        rate: 0.0,
+    });
+    let ops = [
+        BatchOp::UpdateDenom(disabled),
+        BatchOp::DisableDenom("denom2".to_string()),
+        BatchOp::Transfer(multi_send_tx()),
+    ];
+    let results = calculate_batch(&balances(), &definitions(), &ops, &options());
+    assert!(results[0].is_ok());
+    let batch_result = calculate_batch_logged(&[], &definitions(), &ops, &options());
+    let rejection = &batch_result.rejections.rejections[0];
+    assert_eq!(
+        (
+            rejection.index,
+            rejection.category,
+            rejection.denom.as_deref()
+        ),
+        (2, "insufficient_balance", Some("denom1"))
+    );
+    assert_eq!(batch_result.rejections.summary()["insufficient_balance"], 1);
+    assert!(batch_result.summary().starts_with("1 transfers"));
+    let mut jsonl = Vec::new();
+    batch_result.rejections.write_jsonl(&mut jsonl).unwrap();
+    assert_eq!(jsonl.iter().filter(|byte| **byte == b'\n').count(), 1);
+
+    let mut calculator = IncrementalCalculator::with_options(balances(), definitions(), options());
+    calculator
+        .add_input("account1", Coin::new("denom1", 100))
+        .unwrap_err();
+    calculator
+        .add_output("account_recipient", Coin::new("denom1", 100))
+        .unwrap();
+    calculator
+        .update_input(0, Coin::new("denom1", 50))
+        .unwrap_err();
+    calculator
+        .update_output(0, Coin::new("denom1", 50))
+        .unwrap();
+    calculator.remove_output(0).unwrap_err();
+    calculator.remove_input(0).unwrap();
+    assert_eq!(calculator.multi_send().inputs.len(), 0);
+    IncrementalCalculator::new(balances(), definitions());
+}
+
+#[test]
+fn test_transaction_types() {
+    let canonical = multi_send_tx().canonicalize().unwrap();
+    assert_eq!(MultiSend::from(canonical).inputs.len(), 1);
+    let transfers = multi_send_tx().approximate_transfers();
+    assert_eq!(
+        MultiSend::from_transfers(&transfers).unwrap().outputs.len(),
+        1
+    );
+    assert_eq!(
+        Coin::new("denom1", 1_500_000).format_with_decimals(6),
+        "1.500000 denom1 (1500000 base)"
+    );
+    let change = MultiSendBuilder::new()
+        .input("account1", vec![Coin::new("denom1", 100)])
+        .with_change_address("account1")
+        .build()
+        .unwrap();
+    assert_eq!(change.outputs.len(), 1);
+}
+
+#[test]
+fn test_encodings_and_sinks() {
+    let changes = calculate_balance_changes(balances(), definitions(), multi_send_tx()).unwrap();
+    let decoded = decode_changes(&encode_changes(&changes)).unwrap();
+    assert_eq!(fingerprint(&decoded), fingerprint(&changes));
+    calculate_and_verify_fingerprint(
+        &balances(),
+        &definitions(),
+        &multi_send_tx(),
+        fingerprint(&changes),
+    )
+    .unwrap();
+
+    let mut sink = CountingSink::default();
+    calculate_balance_changes_streaming(&balances(), &definitions(), &multi_send_tx(), &mut sink)
+        .unwrap();
+    calculate_streaming_with_options(
+        &balances(),
+        &definitions(),
+        &multi_send_tx(),
+        &options(),
+        &mut sink,
+    )
+    .unwrap();
+    assert_eq!(sink.changes, 7);
+    let mut lines = Vec::new();
+    calculate_balance_changes_streaming(
+        &balances(),
+        &definitions(),
+        &multi_send_tx(),
+        &mut JsonLinesSink::new(&mut lines),
+    )
+    .unwrap();
+    let mut csv = Vec::new();
+    calculate_balance_changes_streaming(
+        &balances(),
+        &definitions(),
+        &multi_send_tx(),
+        &mut CsvSink::new(&mut csv),
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(lines).unwrap().lines().count() + 1,
+        String::from_utf8(csv).unwrap().lines().count()
+    );
+
+    let rejected = calculate_with_options_ref(&[], &definitions(), &multi_send_tx(), &options());
+    let tx_result = to_tx_result(&rejected, None);
+    assert_eq!((tx_result.code, tx_result.codespace.as_str()), (5, "sdk"));
+}
+
+#[test]
+fn test_errors_are_matched_with_a_wildcard() {
+    let error = calculate_balance_changes(Vec::new(), definitions(), multi_send_tx()).unwrap_err();
+    let kind = match &error {
+        CalculationError::InsufficientBalance { .. } => "insufficient balance",
+        _ => "other",
+    };
+    assert_eq!(kind, "insufficient balance");
+    assert_eq!((error.codespace(), error.abci_code()), ("sdk", 5));
+    assert_eq!(
+        (error.category(), error.address(), error.denom()),
+        ("insufficient_balance", Some("account1"), Some("denom1"))
+    );
+    assert!(!error.to_string().is_empty());
+}
+
+#[test]
+fn test_flow_exports() {
+    let changes =
+        calculate_with_options(balances(), definitions(), multi_send_tx(), &options()).unwrap();
+    assert!(viz::to_dot(&changes, &definitions())
+        .unwrap()
+        .starts_with("digraph"));
+    let edges = viz::flow_edges(&multi_send_tx(), &definitions()).unwrap();
+    assert!(edges.iter().any(|edge| edge.to == FlowNode::Burn));
+    assert!(edges.iter().any(|edge| edge.to == FlowNode::Pool));
+    assert!(edges
+        .iter()
+        .any(|edge| edge.from == FlowNode::Account("account1".to_string())));
+    #[cfg(feature = "json")]
+    assert!(viz::to_sankey_json(&changes, &definitions()).unwrap()["nodes"].is_array());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json_entry_point() {
+    use rust_task::encoding::{calculate_json, Scenario, SCENARIO_VERSION};
+
+    let scenario = serde_json::json!({
+        "version": SCENARIO_VERSION,
+        "balances": balances(),
+        "definitions": definitions(),
+        "tx": multi_send_tx(),
+        "options": options(),
+    })
+    .to_string();
+    let parsed: Scenario = serde_json::from_str(&scenario).unwrap();
+    assert_eq!(parsed.version, SCENARIO_VERSION);
+    assert!(calculate_json(&scenario).starts_with("{\"ok\""));
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn test_state_round_trip() {
+    use rust_task::encoding::{load_state, save_state, State};
+
+    let state = State {
+        balances: balances(),
+        definitions: definitions(),
+    };
+    let mut bytes = Vec::new();
+    save_state(&state, &mut bytes).unwrap();
+    assert_eq!(load_state(bytes.as_slice()).unwrap().balances.len(), 2);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_entry_point() {
+    use rust_task::calc::{calculate_balance_changes_async, AsyncDefinitionProvider};
+
+    struct Provider;
+
+    #[async_trait::async_trait]
+    impl AsyncDefinitionProvider for Provider {
+        async fn definition(&self, denom: &str) -> Result<Option<DenomDefinition>, String> {
+            Ok(definitions()
+                .into_iter()
+                .find(|definition| definition.denom == denom))
+        }
+    }
+
+    assert_eq!(
+        calculate_balance_changes_async(balances(), Provider, multi_send_tx())
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+// The public items of every module, one per line: `pub` items and fields as their first line
+// reads, and the variants of public enums. Regenerate the snapshot after an intended change with
+//
+//   UPDATE_API_SNAPSHOT=1 cargo test --test api_stability
+fn public_items() -> String {
+    let sources = [
+        ("calc", include_str!("../src/calc.rs")),
+        ("encoding", include_str!("../src/encoding.rs")),
+        ("error", include_str!("../src/error.rs")),
+        ("lib", include_str!("../src/lib.rs")),
+        ("types", include_str!("../src/types.rs")),
+        ("viz", include_str!("../src/viz.rs")),
+    ];
+    let mut items = Vec::new();
+    for (module, source) in sources {
+        let mut in_enum = false;
+        for line in source.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("pub ") {
+                items.push(format!("{}: {}", module, trimmed));
+                in_enum = line.starts_with("pub enum");
+            } else if line == "}" {
+                in_enum = false;
+            } else if in_enum && line.starts_with("    ") && !line.starts_with("     ") {
+                let variant: String = trimmed
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .collect();
+                if variant.starts_with(|c: char| c.is_ascii_uppercase()) {
+                    items.push(format!("{}:     {}", module, variant));
+                }
+            }
+        }
+    }
+    items.join("\n") + "\n"
+}
+
+#[test]
+fn test_public_items_match_the_snapshot() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/public_api.txt");
+    let actual = public_items();
+    if std::env::var_os("UPDATE_API_SNAPSHOT").is_some() {
+        std::fs::write(path, &actual).unwrap();
+    }
+    let expected = std::fs::read_to_string(path).unwrap_or_default();
+    let (expected_items, actual_items): (BTreeSet<&str>, BTreeSet<&str>) =
+        (expected.lines().collect(), actual.lines().collect());
+    assert!(
+        expected == actual,
+        "the public API changed; removed: {:#?}, added: {:#?}",
+        expected_items.difference(&actual_items).collect::<Vec<_>>(),
+        actual_items.difference(&expected_items).collect::<Vec<_>>()
+    );
+}